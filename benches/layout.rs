@@ -0,0 +1,105 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Compares the throughput of the table layouts, with as many threads as there are CPUs. Each
+//! operation is run on a map with each layout, and the times are printed side by side.
+//!
+//! Run with `cargo bench --bench layout`.
+
+extern crate lockfreehashmap;
+
+use lockfreehashmap::*;
+use std::thread;
+use std::time::Instant;
+
+/// The number of keys in the map, which is enough for the map not to fit in most caches.
+const KEYS: u64 = 1 << 20;
+/// The number of operations that each thread does per benchmark.
+const OPERATIONS: u64 = 1 << 21;
+
+/// A xorshift generator, so that the keys of each thread are spread over the whole map.
+fn next_key(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state % KEYS
+}
+
+fn new_map<'v>(layout: TableLayout) -> LockFreeHashMap<'v, u64, u64> {
+    let map = LockFreeHashMapBuilder::new()
+        .capacity(KEYS as usize * 2)
+        .table_layout(layout)
+        .build();
+    let guard = pin();
+    for key in 0..KEYS {
+        map.insert(key, key, &guard);
+    }
+    map
+}
+
+/// Runs `operation` `OPERATIONS` times on each thread and returns the average time per operation
+/// per thread in nanoseconds.
+fn bench<F>(threads: usize, operation: F) -> f64
+    where F: Fn(u64, &Guard) + Sync
+{
+    let operation = &operation;
+    let start = Instant::now();
+    scope(|scope| {
+        for thread in 0..threads {
+            scope.spawn(move || {
+                let mut state = thread as u64 + 1;
+                let mut guard = pin();
+                for i in 0..OPERATIONS {
+                    operation(next_key(&mut state), &guard);
+                    // Unpin now and then, so that garbage can be collected.
+                    if i % 1024 == 0 {
+                        guard = pin();
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed().as_nanos() as f64 / OPERATIONS as f64
+}
+
+/// Runs `operation` on a map with each layout, and prints the times side by side, along with how
+/// long the `Aligned` layout takes relative to the `Packed` layout.
+fn compare<F>(name: &str, maps: &[LockFreeHashMap<u64, u64>; 2], threads: usize, operation: F)
+    where F: Fn(&LockFreeHashMap<u64, u64>, u64, &Guard) + Sync
+{
+    let operation = &operation;
+    let packed = bench(threads, |key, guard| operation(&maps[0], key, guard));
+    let aligned = bench(threads, |key, guard| operation(&maps[1], key, guard));
+    println!("{:<16} {:>10.1} {:>10.1} {:>10.2}", name, packed, aligned, aligned / packed);
+}
+
+fn main() {
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    println!("{} threads, {} keys, ns/operation/thread", threads, KEYS);
+    println!("{:<16} {:>10} {:>10} {:>10}", "operation", "Packed", "Aligned", "ratio");
+    let maps = &[new_map(TableLayout::Packed), new_map(TableLayout::Aligned)];
+    compare("get", maps, threads, |map, key, guard| {
+        assert_eq!(map.get(&key, guard), Some(&key));
+    });
+    compare("insert", maps, threads, |map, key, guard| {
+        map.insert(key, key, guard);
+    });
+    compare("remove/insert", maps, threads, |map, key, guard| {
+        if map.remove(&key, guard).is_none() {
+            map.insert(key, key, guard);
+        }
+    });
+}
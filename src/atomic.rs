@@ -19,12 +19,41 @@ use std::fmt;
 use std::ops::Deref;
 use std::sync::atomic::Ordering;
 
-pub const ORDERING: Ordering = Ordering::SeqCst;
+/// The ordering of loads of pointers, which synchronize with the read-modify-write operation that
+/// stored the pointer. This makes the value behind the pointer, and everything the storing thread
+/// did before storing it, visible to the loading thread.
+///
+/// None of the state transitions in `map_inner` need a single total order over different
+/// pointers, i.e. `Ordering::SeqCst`. Two threads that race on the same key or value slot both
+/// CAS that same slot, so the modification order of the slot decides which thread went first, and
+/// the thread that loses the race acquires what the winner published. See the `model` module for
+/// model-checked tests of these transitions.
+pub const LOAD_ORDERING: Ordering = Ordering::Acquire;
+
+/// The ordering of compare-and-sets, swaps and tags. A successful CAS releases the new pointer,
+/// and acquires the previous one, which the thread may need to help copy or free. A failed CAS
+/// acquires the current pointer, which is returned to be dereferenced.
+pub const RMW_ORDERING: Ordering = Ordering::AcqRel;
 
 /// A wrapper around [Shared], for values that we know are not null and are safe to dereference.
 pub struct NotNull<'t, T: 't>(Shared<'t, T>);
 
 impl<'t, T> NotNull<'t, T> {
+    /// Creates a pointer to a value that is borrowed for `'t`, e.g. a value that is shared by many
+    /// atomic pointers, rather than owned by one of them.
+    pub fn from_ref(value: &'t T) -> Self {
+        NotNull(Shared::from(value as *const T))
+    }
+    /// Returns true if the lowest bit of the pointer is set. See `NotNull::with_tag()`.
+    pub fn is_tagged(&self) -> bool {
+        self.0.tag() == 1
+    }
+    /// Returns the same pointer with the lowest bit set, which `T` must be aligned enough for.
+    /// Dereferencing the pointer ignores the tag.
+    pub fn with_tag(&self) -> Self {
+        debug_assert!(::std::mem::align_of::<T>() >= 2, "`T` has no room for a tag");
+        NotNull(self.0.with_tag(1))
+    }
     /// This is effectively just weakening the guarantees around this type.
     pub fn as_maybe_null(&self) -> MaybeNull<'t, T> {
         MaybeNull(self.0)
@@ -36,8 +65,8 @@ impl<'t, T> NotNull<'t, T> {
     pub fn deref<'f>(&'f self) -> &'t T {
         // This is safe because
         // 1) This type is only created in situations it's guaranteed not to be null
-        // 2) This type only created from types in this module, which never uses
-        // `Ordering::Relaxed`.
+        // 2) This type only created from types in this module, which only dereference pointers
+        // that were loaded with at least `Ordering::Acquire`.
         // 3) `T: 't`, i.e. T outlives 't, so we can return a reference to it
         debug_assert!(!self.0.is_null());
         unsafe { self.0.deref() }
@@ -51,6 +80,15 @@ impl<'t, T> NotNull<'t, T> {
         debug_assert!(!shared.is_null());
         drop(shared.into_owned());
     }
+    /// Take back ownership of the underlying value behind this pointer.
+    ///
+    /// # Unsafe
+    /// This is unsafe for the same reasons as `NotNull::drop()`. In addition, no other thread can
+    /// have a reference to the underlying value.
+    pub unsafe fn into_box(self) -> Box<T> {
+        debug_assert!(!self.0.is_null());
+        self.0.into_owned().into_box()
+    }
 }
 
 impl<'t, T: fmt::Debug> fmt::Debug for NotNull<'t, T> {
@@ -85,6 +123,16 @@ impl<T> NotNullOwned<T> {
     pub fn into_owned(self) -> Owned<T> {
         self.0
     }
+    /// Converts this into a [NotNull] without dropping the underlying value, so that it can still
+    /// be dereferenced after being stored in an [AtomicPtr].
+    ///
+    /// # Unsafe
+    /// This is unsafe because the returned pointer isn't tied to any `Guard`. The caller must
+    /// either transfer ownership of the value to some [AtomicPtr], or take back ownership of it
+    /// with `NotNull::drop()` or `NotNull::into_box()`.
+    pub unsafe fn into_not_null<'t>(self) -> NotNull<'t, T> {
+        NotNull(Shared::from(Box::into_raw(self.0.into_box()) as *const T))
+    }
 }
 
 impl<T: fmt::Debug> fmt::Debug for NotNullOwned<T> {
@@ -96,9 +144,8 @@ impl<T: fmt::Debug> fmt::Debug for NotNullOwned<T> {
 impl<T> Deref for NotNullOwned<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        // This is safe because this is only created from types in this module, which never uses
-        // `Ordering::Relaxed` (or at least never returns pointers to values retrieved with
-        // Relaxed).
+        // This is safe because this is only created from types in this module, which never return
+        // pointers to values retrieved with `Ordering::Relaxed`.
         self.0.deref()
     }
 }
@@ -130,18 +177,6 @@ impl<'t, T> MaybeNull<'t, T> {
             false => Some(NotNull(self.0)),
         }
     }
-    /// Drop the underlying value behind this pointer if it's not null and after enough epochs have
-    /// passed.
-    ///
-    /// # Unsafe
-    /// This is unsafe because only one thread can call this for any single underlying value.
-    pub unsafe fn try_defer_drop(self, guard: &Guard) {
-        if !self.as_shared().is_null() {
-            guard.defer(move || {
-                self.as_shared().into_owned();
-            })
-        }
-    }
 }
 
 impl<'t, T: fmt::Debug> fmt::Debug for MaybeNull<'t, T> {
@@ -166,7 +201,7 @@ impl<T> AtomicPtr<T> {
     }
 
     pub fn load<'g>(&self, guard: &'g Guard) -> MaybeNull<'g, T> {
-        MaybeNull(self.0.load(ORDERING, guard))
+        MaybeNull(self.0.load(LOAD_ORDERING, guard))
     }
 
     /// Check if the pointer is not null (i.e. the value exists), using `Ordering::Relaxed`.
@@ -181,7 +216,7 @@ impl<T> AtomicPtr<T> {
     /// This is unsafe because there must be no other pointers that can ever access this
     /// `AtomicPtr` after this function is called. This is intended to be used for [Drop::drop()].
     pub unsafe fn take<'g>(&self, guard: &'g Guard) -> Option<Box<T>> {
-        let shared = self.0.swap(Shared::null(), ORDERING, guard);
+        let shared = self.0.swap(Shared::null(), RMW_ORDERING, guard);
         match shared.is_null() {
             true => None,
             false => Some(shared.into_owned().into_box())
@@ -191,7 +226,7 @@ impl<T> AtomicPtr<T> {
     pub fn compare_and_set<'g>(&self, compare: MaybeNull<T>, set: NotNull<'g, T>, guard: &'g Guard)
         -> Result<NotNull<'g, T>, (MaybeNull<'g, T>, NotNull<'g, T>)>
     {
-        self.0.compare_and_set(compare.as_shared(), set.as_shared(), ORDERING, guard)
+        self.0.compare_and_set(compare.as_shared(), set.as_shared(), RMW_ORDERING, guard)
             .map(|set| NotNull(set))
             .map_err(|e| (MaybeNull(e.current), NotNull(e.new)))
     }
@@ -202,7 +237,7 @@ impl<T> AtomicPtr<T> {
         guard: &'g Guard
     ) -> Result<NotNull<'g, T>, (NotNull<'g, T>, NotNull<'g, T>)>
     {
-        self.0.compare_and_set(Shared::null(), set.as_shared(), ORDERING, guard)
+        self.0.compare_and_set(Shared::null(), set.as_shared(), RMW_ORDERING, guard)
             .map(|set| NotNull(set))
             .map_err(|e| (NotNull(e.current), NotNull(e.new)))
     }
@@ -213,7 +248,7 @@ impl<T> AtomicPtr<T> {
         guard: &'g Guard
     ) -> Result<NotNull<'g, T>, (NotNull<'g, T>, NotNullOwned<T>)>
     {
-        self.0.compare_and_set(Shared::null(), set.into_owned(), ORDERING, guard)
+        self.0.compare_and_set(Shared::null(), set.into_owned(), RMW_ORDERING, guard)
             .map(|set| NotNull(set))
             .map_err(|e| (NotNull(e.current), NotNullOwned(e.new)))
     }
@@ -225,33 +260,21 @@ impl<T> AtomicPtr<T> {
         guard: &'g Guard
     ) -> Result<NotNull<'g, T>, (MaybeNull<'g, T>, NotNullOwned<T>)>
     {
-        self.0.compare_and_set(compare.as_shared(), set.into_owned(), ORDERING, guard)
-            .map(|set| NotNull(set))
-            .map_err(|e| (MaybeNull(e.current), NotNullOwned(e.new)))
-    }
-
-    pub fn compare_and_set_owned_weak<'g>(
-        &self,
-        compare: MaybeNull<T>,
-        set: NotNullOwned<T>,
-        guard: &'g Guard
-    ) -> Result<NotNull<'g, T>, (MaybeNull<'g, T>, NotNullOwned<T>)>
-    {
-        self.0.compare_and_set_weak(compare.as_shared(), set.into_owned(), ORDERING, guard)
+        self.0.compare_and_set(compare.as_shared(), set.into_owned(), RMW_ORDERING, guard)
             .map(|set| NotNull(set))
             .map_err(|e| (MaybeNull(e.current), NotNullOwned(e.new)))
     }
 
     pub fn tag<'g>(&self, guard: &'g Guard) {
-        self.0.fetch_or(1, ORDERING, guard);
+        self.0.fetch_or(1, RMW_ORDERING, guard);
     }
 
     pub fn is_tagged<'g>(&self, guard: &'g Guard) -> bool {
-        self.0.fetch_or(0, ORDERING, guard).tag() == 1
+        self.0.fetch_or(0, RMW_ORDERING, guard).tag() == 1
     }
 
     pub unsafe fn try_drop(&mut self, guard: &Guard) {
-        let inner = self.0.swap(Shared::null(), ORDERING, &guard);
+        let inner = self.0.swap(Shared::null(), RMW_ORDERING, &guard);
         if !inner.is_null() {
             inner.into_owned();
         }
@@ -274,12 +297,12 @@ impl<T> AtomicBox<T> {
     }
 
     pub fn load<'g>(&self, guard: &'g Guard) -> NotNull<'g, T> {
-        NotNull(self.0.load(ORDERING, guard))
+        NotNull(self.0.load(LOAD_ORDERING, guard))
     }
 
     pub fn replace<'g>(&self, value: T) {
         let guard = &::pin();
-        let contents = self.0.swap(Owned::new(value), ORDERING, &guard);
+        let contents = self.0.swap(Owned::new(value), RMW_ORDERING, &guard);
         unsafe { guard.defer(move || contents.into_owned()); }
     }
 
@@ -291,7 +314,7 @@ impl<T> AtomicBox<T> {
     )
         -> Result<NotNull<'g, T>, (NotNull<'g, T>, NotNull<T>)>
     {
-        self.0.compare_and_set(compare.0, set.0, ORDERING, guard)
+        self.0.compare_and_set(compare.0, set.0, RMW_ORDERING, guard)
             .map(|set| NotNull(set))
             .map_err(|e| (NotNull(e.current), NotNull(e.new)))
     }
@@ -307,7 +330,7 @@ impl<T: fmt::Debug> fmt::Debug for AtomicBox<T> {
 impl<T> Drop for AtomicBox<T> {
     fn drop(&mut self) {
         let guard = ::pin();
-        let inner = self.0.swap(Shared::null(), ORDERING, &guard);
+        let inner = self.0.swap(Shared::null(), RMW_ORDERING, &guard);
         debug_assert!(!inner.is_null());
         if !inner.is_null() {
             unsafe { inner.into_owned(); }
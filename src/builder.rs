@@ -0,0 +1,347 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements [LockFreeHashMapBuilder], which creates maps with non-default settings.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use atomic::AtomicBox;
+use layout::TableLayout;
+use map_inner::MapInner;
+use resize::{DefaultResizePolicy, ResizeContention, ResizePolicy};
+use {LockFreeHashMap, LockFreeHashMapU64, LockFreeHashSet};
+
+/// The settings of a map. Every `MapInner` that a map is resized into gets a clone of these.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub resize_policy: Arc<dyn ResizePolicy>,
+    pub resize_contention: ResizeContention,
+    /// If set, the map shrinks after a removal leaves fewer than `capacity * auto_shrink` live
+    /// key/value pairs.
+    pub auto_shrink: Option<f64>,
+    pub table_layout: TableLayout,
+    /// The number of key slots that a thread claims at a time when copying a map into a newer map.
+    pub copy_chunk_size: usize,
+    /// The maximum number of chunks that a single operation helps copy into a newer map. If
+    /// `None`, then the insert that finds the map full copies all of it, and every other operation
+    /// copies one chunk.
+    pub copy_budget: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            resize_policy: Arc::new(DefaultResizePolicy),
+            resize_contention: ResizeContention::default(),
+            auto_shrink: None,
+            table_layout: TableLayout::default(),
+            copy_chunk_size: ::COPY_CHUNK_SIZE,
+            copy_budget: None,
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The resize policy is a trait object, which doesn't implement `Debug`.
+        f.debug_struct("Config")
+            .field("resize_contention", &self.resize_contention)
+            .field("auto_shrink", &self.auto_shrink)
+            .field("table_layout", &self.table_layout)
+            .field("copy_chunk_size", &self.copy_chunk_size)
+            .field("copy_budget", &self.copy_budget)
+            .finish()
+    }
+}
+
+/// A builder for a [LockFreeHashMap] with non-default settings.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// let map: LockFreeHashMap<u32, String> = LockFreeHashMapBuilder::new()
+///     .capacity(100)
+///     .resize_policy(DefaultResizePolicy)
+///     .build();
+/// assert_eq!(map.capacity(), 128);
+/// ```
+#[derive(Debug)]
+pub struct LockFreeHashMapBuilder<S = RandomState> {
+    capacity: usize,
+    hasher: S,
+    config: Config,
+}
+
+impl LockFreeHashMapBuilder<RandomState> {
+    /// Creates a builder with the same settings as `LockFreeHashMap::new()`.
+    pub fn new() -> Self {
+        LockFreeHashMapBuilder {
+            capacity: LockFreeHashMap::<(), ()>::DEFAULT_CAPACITY,
+            hasher: RandomState::new(),
+            config: Config::default(),
+        }
+    }
+}
+
+impl Default for LockFreeHashMapBuilder<RandomState> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> LockFreeHashMapBuilder<S> {
+    /// Sets the initial capacity of the map. Uses the next power of two if `capacity` is not a
+    /// power of two.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets the hasher used to hash the keys.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    ///
+    /// let map: LockFreeHashMap<u32, u32, _> = LockFreeHashMapBuilder::new()
+    ///     .hasher(BuildHasherDefault::<DefaultHasher>::default())
+    ///     .build();
+    /// map.insert(1, 2, &lockfreehashmap::pin());
+    /// ```
+    pub fn hasher<T>(self, hasher: T) -> LockFreeHashMapBuilder<T> {
+        LockFreeHashMapBuilder {
+            capacity: self.capacity,
+            hasher,
+            config: self.config,
+        }
+    }
+
+    /// Sets the [ResizePolicy] that decides the capacity of the map whenever it's resized. The
+    /// default is [DefaultResizePolicy](::DefaultResizePolicy).
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// // Always grow eight times bigger.
+    /// struct Aggressive;
+    /// impl ResizePolicy for Aggressive {
+    ///     fn new_capacity(&self, stats: &ResizeStats) -> usize {
+    ///         stats.capacity * 8
+    ///     }
+    /// }
+    ///
+    /// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+    ///     .capacity(1)
+    ///     .resize_policy(Aggressive)
+    ///     .build();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(1, 1, &guard);
+    /// map.insert(2, 2, &guard);
+    /// assert_eq!(map.capacity(), 8);
+    /// ```
+    pub fn resize_policy<P: ResizePolicy + 'static>(mut self, policy: P) -> Self {
+        self.config.resize_policy = Arc::new(policy);
+        self
+    }
+
+    /// Sets the [ResizeContention] strategy, i.e. what a thread does while another thread is
+    /// allocating a newer map. The default is to sleep for one millisecond per megabyte.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+    ///     .resize_contention(ResizeContention::None)
+    ///     .build();
+    /// ```
+    pub fn resize_contention(mut self, strategy: ResizeContention) -> Self {
+        self.config.resize_contention = strategy;
+        self
+    }
+
+    /// Makes the map shrink automatically, like calling `LockFreeHashMap::shrink_to_fit()`, when a
+    /// removal leaves fewer than `capacity * min_load` live key/value pairs in the map. The map
+    /// never automatically shrinks below `LockFreeHashMap::DEFAULT_CAPACITY`.
+    ///
+    /// Unlike `shrink_to_fit()`, the removal doesn't copy the whole map into the smaller map. It
+    /// copies as much as any other operation on a map that is being resized, which is set by
+    /// `copy_budget()`, and the operations after it copy the rest.
+    ///
+    /// Maps don't shrink automatically by default.
+    ///
+    /// # Panics
+    /// Panics if `min_load` isn't greater than 0 and at most 0.25. A map that was just shrunk is
+    /// more than a quarter full, so a larger `min_load` could shrink the map after every removal.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+    ///     .auto_shrink(0.125)
+    ///     .build();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..1000 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert!(map.capacity() >= 1024);
+    /// for i in 0..990 {
+    ///     map.remove(&i, &guard);
+    /// }
+    /// assert!(map.capacity() <= 64);
+    /// ```
+    pub fn auto_shrink(mut self, min_load: f64) -> Self {
+        assert!(min_load > 0.0 && min_load <= 0.25, "`min_load` must be in the range (0, 0.25]");
+        self.config.auto_shrink = Some(min_load);
+        self
+    }
+
+    /// Sets the [TableLayout] of the map's key slots. The default is `TableLayout::Packed`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+    ///     .table_layout(TableLayout::Aligned)
+    ///     .build();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.get(&42, &guard), Some(&42));
+    /// ```
+    pub fn table_layout(mut self, layout: TableLayout) -> Self {
+        self.config.table_layout = layout;
+        self
+    }
+
+    /// Sets the number of key slots that a thread claims at a time when it helps copy a map into a
+    /// newer map during a resize. The default is [COPY_CHUNK_SIZE](::COPY_CHUNK_SIZE).
+    ///
+    /// Smaller chunks let more threads copy at the same time and make each operation that helps
+    /// copy faster, but the threads contend more on claiming chunks.
+    ///
+    /// # Panics
+    /// Panics if `size` is 0.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+    ///     .copy_chunk_size(8)
+    ///     .build();
+    /// ```
+    pub fn copy_chunk_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "`size` must be greater than 0");
+        self.config.copy_chunk_size = size;
+        self
+    }
+
+    /// Limits how much of a map a single operation copies while the map is resized, to at most
+    /// `chunks` chunks of `copy_chunk_size()` key slots.
+    ///
+    /// By default, the insert that finds the map full and starts a resize copies the entire map,
+    /// which makes that one insert take as long as copying the map. Every other operation that
+    /// finds the map being resized copies one chunk. With a budget, the insert that starts the
+    /// resize copies at most `chunks` chunks too, and so does every other operation, so that the
+    /// copy is spread over more operations. This bounds how long any single operation takes, at
+    /// the cost of the resize taking longer to finish.
+    ///
+    /// Methods that need the resize to finish, e.g. `reserve()`, `shrink_to_fit()` and `iter()`,
+    /// still copy the rest of the map.
+    ///
+    /// # Panics
+    /// Panics if `chunks` is 0, because then a resize might never finish.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+    ///     .capacity(1024)
+    ///     .copy_chunk_size(16)
+    ///     .copy_budget(2)
+    ///     .build();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..10_000 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert!((0..10_000).all(|i| map.get(&i, &guard) == Some(&i)));
+    /// ```
+    pub fn copy_budget(mut self, chunks: usize) -> Self {
+        assert!(chunks > 0, "`chunks` must be greater than 0");
+        self.config.copy_budget = Some(chunks);
+        self
+    }
+
+    /// Creates the map.
+    pub fn build<'v, K, V>(self) -> LockFreeHashMap<'v, K, V, S>
+        where K: Hash + Eq,
+              V: PartialEq,
+              S: BuildHasher + Clone,
+    {
+        LockFreeHashMap {
+            inner: AtomicBox::new(MapInner::with_config(self.capacity, self.hasher, self.config)),
+        }
+    }
+
+    /// Creates a set with these settings instead of a map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set: LockFreeHashSet<u32> = LockFreeHashMapBuilder::new()
+    ///     .capacity(1024)
+    ///     .table_layout(TableLayout::Aligned)
+    ///     .build_set();
+    /// set.insert(1);
+    /// assert_eq!(set.capacity(), 1024);
+    /// ```
+    pub fn build_set<T>(self) -> LockFreeHashSet<T, S>
+        where T: Hash + Eq,
+              S: BuildHasher + Clone,
+    {
+        LockFreeHashSet::with_config(self.capacity, self.hasher, self.config)
+    }
+
+    /// Creates a [LockFreeHashMapU64] with these settings instead of a map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMapU64<u32> = LockFreeHashMapBuilder::new()
+    ///     .auto_shrink(0.125)
+    ///     .build_u64();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..1000 {
+    ///     map.insert(i, i as u32, &guard);
+    /// }
+    /// assert!(map.capacity() >= 1024);
+    /// for i in 0..990 {
+    ///     map.remove(i, &guard);
+    /// }
+    /// assert!(map.capacity() <= 64);
+    /// ```
+    pub fn build_u64<'v, V>(self) -> LockFreeHashMapU64<'v, V, S>
+        where V: PartialEq,
+              S: BuildHasher + Clone,
+    {
+        LockFreeHashMapU64::with_config(self.capacity, self.hasher, self.config)
+    }
+}
@@ -0,0 +1,175 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements [StripedCounter], a counter that many threads can update at the same
+//! time without all of them writing to the same cache line.
+//!
+//! This is a simpler version of the `ConcurrentAutoTable` in Dr. Click's hash map. Instead of
+//! growing the number of stripes when threads contend, there is one stripe per CPU, up to a limit
+//! that depends on the capacity of the map.
+
+use std::cell::Cell;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::time::Instant;
+use std::thread;
+
+use layout::CachePadded;
+
+/// Used to give each thread its own stripe, round-robin.
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// The time that `StripedCounter::cached_at` is measured from.
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD_INDEX.fetch_add(1, Ordering::Relaxed);
+    /// The number of calls to `StripedCounter::estimate()` on this thread, which only reads the
+    /// clock every `StripedCounter::CALLS_PER_CLOCK_READ` calls.
+    static ESTIMATE_CALLS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A counter that is split into several stripes. Each thread updates only its own stripe, and
+/// the stripes are summed when the counter is read.
+///
+/// A single stripe can wrap around, e.g. if one thread only increments and another only
+/// decrements. This is fine because the stripes are summed as `isize`s, which wrap back around.
+/// The sum itself can be negative though, if it includes a decrement on one stripe but not the
+/// increment on another stripe that came before it.
+#[derive(Debug)]
+pub struct StripedCounter {
+    /// Each stripe is on its own cache line, so that updating it doesn't slow down other stripes.
+    stripes: Box<[CachePadded<AtomicUsize>]>,
+    /// The sum of the stripes at the time in `cached_at`, used by `estimate()`.
+    cached_sum: AtomicIsize,
+    /// One more than the number of milliseconds since `CLOCK_START` when `cached_sum` was summed,
+    /// or 0 if it never was.
+    cached_at: AtomicUsize,
+}
+
+impl StripedCounter {
+    /// `estimate()` only uses the cached sum if there are more stripes than this.
+    const MAX_STRIPES_TO_SUM: usize = 8;
+    /// The number of key slots of a map for each stripe of its counter. Every stripe takes up a
+    /// whole cache line, so small maps, and small tables in a chain of newer maps, only get a few.
+    const SLOTS_PER_STRIPE: usize = 64;
+    /// How often each thread checks the clock in `estimate()`.
+    const CALLS_PER_CLOCK_READ: usize = 16;
+
+    /// Creates a counter at zero for a map with `capacity` key slots, with one stripe per CPU but
+    /// at most one per `SLOTS_PER_STRIPE` key slots.
+    pub fn new(capacity: usize) -> Self {
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let stripes = ::std::cmp::min(cpus, capacity / Self::SLOTS_PER_STRIPE)
+            .checked_next_power_of_two()
+            .unwrap_or(1);
+        StripedCounter {
+            stripes: (0..stripes).map(|_| CachePadded::default()).collect::<Vec<_>>()
+                .into_boxed_slice(),
+            cached_sum: AtomicIsize::new(0),
+            cached_at: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the stripe of the current thread.
+    fn stripe(&self) -> &AtomicUsize {
+        let index = THREAD_INDEX.with(|index| *index);
+        &self.stripes[index & (self.stripes.len() - 1)].0
+    }
+
+    /// Adds one to the counter.
+    ///
+    /// The counter doesn't publish any other data, so all of its operations are relaxed. A thread
+    /// that has joined the threads that updated the counter still sees all of their updates.
+    pub fn increment(&self) {
+        self.stripe().fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Subtracts one from the counter.
+    pub fn decrement(&self) {
+        self.stripe().fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns the sum of all stripes. If other threads update the counter at the same time, then
+    /// some of their updates may or may not be included, so the sum can be negative even if the
+    /// counter never is.
+    pub fn sum(&self) -> isize {
+        self.stripes.iter()
+            .fold(0isize, |sum, stripe| sum.wrapping_add(stripe.0.load(Ordering::Relaxed) as isize))
+    }
+
+    /// Returns an estimate of the sum, which is faster than `sum()` when there are many stripes.
+    ///
+    /// Each thread reads the clock once every `CALLS_PER_CLOCK_READ` calls, and sums the stripes
+    /// again if the estimate was summed in an earlier millisecond. So the estimate is at most about
+    /// a millisecond old, except for the calls in between, which return it without checking.
+    pub fn estimate(&self) -> isize {
+        if self.stripes.len() <= Self::MAX_STRIPES_TO_SUM {
+            return self.sum();
+        }
+        let calls = ESTIMATE_CALLS.with(|calls| calls.replace(calls.get().wrapping_add(1)));
+        let cached_at = self.cached_at.load(Ordering::Relaxed);
+        if cached_at == 0 || calls % Self::CALLS_PER_CLOCK_READ == 0 {
+            let start = CLOCK_START.get_or_init(Instant::now);
+            let now = (start.elapsed().as_millis() as usize).wrapping_add(1);
+            if cached_at != now {
+                self.cached_sum.store(self.sum(), Ordering::Relaxed);
+                self.cached_at.store(now, Ordering::Relaxed);
+            }
+        }
+        self.cached_sum.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scope;
+
+    #[test]
+    fn test_striped_counter() {
+        let counter = &StripedCounter::new(usize::MAX);
+        scope(|scope| {
+            // Some threads only increment and others only decrement, so that single stripes wrap
+            // around when they share one.
+            for thread in 0..8 {
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        if thread % 2 == 0 {
+                            counter.increment();
+                            counter.increment();
+                        } else {
+                            counter.decrement();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.sum(), 4000);
+        assert_eq!(counter.estimate(), 4000);
+    }
+
+    #[test]
+    fn test_striped_counter_size() {
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        assert_eq!(StripedCounter::new(1).stripes.len(), 1);
+        assert_eq!(StripedCounter::new(StripedCounter::SLOTS_PER_STRIPE).stripes.len(), 1);
+        assert_eq!(
+            StripedCounter::new(usize::MAX).stripes.len(),
+            cpus.checked_next_power_of_two().unwrap()
+        );
+    }
+}
@@ -0,0 +1,246 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements the entry API returned by [::LockFreeHashMap::entry()].
+//!
+//! Unlike the entries of [HashMap](::std::collections::HashMap), these entries don't borrow the
+//! map mutably, so other threads can still change the map while an entry exists. Whether an
+//! entry is occupied or vacant is therefore only true at the point in time the entry was created.
+//! Each method that writes to the map only publishes its value if the state it expects still
+//! holds, using `MapInner::put_if_match()`.
+
+use std::hash::{BuildHasher, Hash};
+
+use {Guard, LockFreeHashMap};
+
+/// A view into a single entry in a map, which may either be vacant or occupied.
+///
+/// This is constructed from [LockFreeHashMap::entry()].
+pub enum Entry<'guard, 'v: 'guard, K: 'guard, V: 'v, S: 'guard> {
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'guard, 'v, K, V, S>),
+    /// A vacant entry.
+    Vacant(VacantEntry<'guard, 'v, K, V, S>),
+}
+
+/// A view into an occupied entry in a [LockFreeHashMap]. It is part of the [Entry] enum.
+pub struct OccupiedEntry<'guard, 'v: 'guard, K: 'guard, V: 'v, S: 'guard> {
+    map: &'guard LockFreeHashMap<'v, K, V, S>,
+    key: K,
+    value: &'guard V,
+    guard: &'guard Guard,
+}
+
+/// A view into a vacant entry in a [LockFreeHashMap]. It is part of the [Entry] enum.
+pub struct VacantEntry<'guard, 'v: 'guard, K: 'guard, V: 'v, S: 'guard> {
+    map: &'guard LockFreeHashMap<'v, K, V, S>,
+    key: K,
+    guard: &'guard Guard,
+}
+
+impl<'guard, 'v: 'guard, K, V, S> Entry<'guard, 'v, K, V, S>
+    where K: 'guard + Hash + Eq,
+          V: PartialEq,
+          S: 'guard + BuildHasher + Clone,
+{
+    /// Ensures a value is in the entry by inserting `default` if empty, and returns a reference to
+    /// the value in the entry.
+    ///
+    /// If another thread inserts a value for this key first, then `default` is dropped and a
+    /// reference to the other thread's value is returned instead.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.entry("poneyland", &guard).or_insert(12), &12);
+    /// assert_eq!(map.entry("poneyland", &guard).or_insert(15), &12);
+    /// ```
+    pub fn or_insert(self, default: V) -> &'guard V {
+        match self {
+            Entry::Occupied(entry) => entry.get(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of `default` if empty, and returns
+    /// a reference to the value in the entry.
+    ///
+    /// `default` is only called if the entry was vacant. If another thread inserts a value for
+    /// this key before the result of `default` is published, then that result is dropped and a
+    /// reference to the other thread's value is returned instead.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// let value = map.entry("poneyland", &guard).or_insert_with(|| "hoho".to_string());
+    /// assert_eq!(value, "hoho");
+    /// let value = map.entry("poneyland", &guard).or_insert_with(|| unreachable!());
+    /// assert_eq!(value, "hoho");
+    /// ```
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'guard V {
+        match self {
+            Entry::Occupied(entry) => entry.get(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Provides access to an occupied entry before any potential inserts into the map, by
+    /// replacing its value with the result of `f`.
+    ///
+    /// `f` is given the current value of the key, which another thread may have replaced since the
+    /// entry was created. Its result is only published if the value hasn't changed in the
+    /// meantime, so concurrent updates aren't lost. Otherwise, `f` is called again with the newer
+    /// value, like in [LockFreeHashMap::compute()]. If the key no longer has a value in the map,
+    /// then a vacant entry is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.entry("poneyland", &guard).and_modify(|v| v + 1).or_insert(42);
+    /// assert_eq!(map.get(&"poneyland", &guard), Some(&42));
+    /// map.entry("poneyland", &guard).and_modify(|v| v + 1).or_insert(42);
+    /// assert_eq!(map.get(&"poneyland", &guard), Some(&43));
+    /// ```
+    pub fn and_modify<F: FnMut(&V) -> V>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(entry) => match entry.map.update(&entry.key, f, entry.guard) {
+                Some(value) => Entry::Occupied(OccupiedEntry { value, ..entry }),
+                None => Entry::Vacant(VacantEntry::new(entry.map, entry.key, entry.guard)),
+            },
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+}
+
+impl<'guard, 'v: 'guard, K, V, S> OccupiedEntry<'guard, 'v, K, V, S>
+    where K: 'guard + Hash + Eq,
+          V: PartialEq,
+          S: 'guard + BuildHasher + Clone,
+{
+    pub(crate) fn new(
+        map: &'guard LockFreeHashMap<'v, K, V, S>,
+        key: K,
+        value: &'guard V,
+        guard: &'guard Guard,
+    ) -> Self {
+        OccupiedEntry { map, key, value, guard }
+    }
+
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns a reference to the value in the entry at the point in time that the entry was
+    /// created.
+    pub fn get(&self) -> &'guard V {
+        self.value
+    }
+
+    /// Sets the value of the entry, returning the entry's previous value, if any. Because another
+    /// thread may have removed the key in the meantime, the key is inserted again if necessary.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert("poneyland", 12, &guard);
+    /// if let Entry::Occupied(entry) = map.entry("poneyland", &guard) {
+    ///     assert_eq!(entry.insert(15), Some(&12));
+    /// }
+    /// assert_eq!(map.get(&"poneyland", &guard), Some(&15));
+    /// ```
+    pub fn insert(self, value: V) -> Option<&'guard V> {
+        self.map.insert(self.key, value, self.guard)
+    }
+
+    /// Removes the entry from the map, returning its value if it was still in the map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert("poneyland", 12, &guard);
+    /// if let Entry::Occupied(entry) = map.entry("poneyland", &guard) {
+    ///     assert_eq!(entry.remove(), Some(&12));
+    /// }
+    /// assert!(!map.contains_key(&"poneyland"));
+    /// ```
+    pub fn remove(self) -> Option<&'guard V> {
+        self.map.remove(&self.key, self.guard)
+    }
+}
+
+impl<'guard, 'v: 'guard, K, V, S> VacantEntry<'guard, 'v, K, V, S>
+    where K: 'guard + Hash + Eq,
+          V: PartialEq,
+          S: 'guard + BuildHasher + Clone,
+{
+    pub(crate) fn new(map: &'guard LockFreeHashMap<'v, K, V, S>, key: K, guard: &'guard Guard)
+        -> Self
+    {
+        VacantEntry { map, key, guard }
+    }
+
+    /// Returns a reference to the key that would be used when inserting a value through this
+    /// entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Sets the value of the entry if the key still has no value, and returns a reference to the
+    /// value in the map.
+    ///
+    /// If another thread inserts a value for this key first, then `value` is dropped and a
+    /// reference to the other thread's value is returned instead.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// if let Entry::Vacant(entry) = map.entry("poneyland", &guard) {
+    ///     assert_eq!(entry.insert(37), &37);
+    /// }
+    /// assert_eq!(map.get(&"poneyland", &guard), Some(&37));
+    /// ```
+    pub fn insert(self, value: V) -> &'guard V {
+        match self.map.insert_if_absent(self.key, value, self.guard) {
+            Ok(value) | Err(value) => value,
+        }
+    }
+}
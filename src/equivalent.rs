@@ -0,0 +1,96 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the [Equivalent] trait, which decides which types can be used to look up
+//! the keys of a map.
+
+use std::borrow::Borrow;
+
+/// A type that can be compared with a key of type `K`, so that it can be used to look up a key in
+/// a map without constructing a `K`.
+///
+/// This is implemented for every `Q` that `K` can be borrowed as, like in the maps of the standard
+/// library. For other types, such as a borrowed form of a tuple, it can be implemented by hand.
+/// If a value is equivalent to a key, then they must have the same hash.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// // A `(String, u32)` can't be borrowed as a `(&str, u32)`, so look it up with a type that is
+/// // equivalent to it instead. Deriving `Hash` hashes the fields in the same way as a tuple.
+/// #[derive(Hash)]
+/// struct Lookup<'a>(&'a str, u32);
+///
+/// impl<'a> Equivalent<(String, u32)> for Lookup<'a> {
+///     fn equivalent(&self, key: &(String, u32)) -> bool {
+///         self.0 == key.0 && self.1 == key.1
+///     }
+/// }
+///
+/// let map = LockFreeHashMap::<(String, u32), u32>::new();
+/// let guard = lockfreehashmap::pin();
+/// map.insert(("one".to_string(), 1), 11, &guard);
+/// assert_eq!(map.get(&Lookup("one", 1), &guard), Some(&11));
+/// assert_eq!(map.get(&Lookup("one", 2), &guard), None);
+/// ```
+pub trait Equivalent<K: ?Sized> {
+    /// Returns true if `self` is equal to `key`.
+    fn equivalent(&self, key: &K) -> bool;
+}
+
+impl<Q: ?Sized, K: ?Sized> Equivalent<K> for Q
+    where Q: Eq,
+          K: Borrow<Q>,
+{
+    fn equivalent(&self, key: &K) -> bool {
+        *self == *key.borrow()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test::run_threads;
+    use {pin, LockFreeHashMap};
+
+    #[test]
+    fn test_equivalent_keys() {
+        #[derive(Hash)]
+        struct Lookup<'a>(&'a str, u32);
+        impl<'a> Equivalent<(String, u32)> for Lookup<'a> {
+            fn equivalent(&self, key: &(String, u32)) -> bool {
+                self.0 == key.0 && self.1 == key.1
+            }
+        }
+        let map = &LockFreeHashMap::<(String, u32), u32>::with_capacity(1);
+        run_threads(4, 64, |keys, guard| {
+            for i in keys {
+                map.insert((i.to_string(), i), i, guard);
+            }
+        });
+        let guard = pin();
+        for i in 0..256 {
+            let key = i.to_string();
+            assert!(map.contains_key(&Lookup(&key, i)));
+            assert!(!map.contains_key(&Lookup(&key, i + 1)));
+            assert_eq!(map.replace(&Lookup(&key, i), i + 1, &guard), Some(&i));
+            assert_eq!(map.get(&Lookup(&key, i), &guard), Some(&(i + 1)));
+            assert_eq!(map.remove(&Lookup(&key, i), &guard), Some(&(i + 1)));
+            assert_eq!(map.get(&Lookup(&key, i), &guard), None);
+        }
+        assert_eq!(map.len(), 0);
+    }
+}
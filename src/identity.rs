@@ -0,0 +1,180 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements [LockFreeIdentityHashMap], whose keys are compared by address, like the
+//! `NonBlockingIdentityHashMap` in Dr. Click's library.
+//!
+//! Rather than being a separate map, it's a [LockFreeHashMap] whose keys are wrapped in
+//! [ByAddress], which hashes and compares a pointer by the address it points to. So it uses the
+//! same `MapInner` as every other map.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+use LockFreeHashMap;
+
+/// A concurrent, lock-free hash map whose keys are compared by the address they point to, instead
+/// of by their contents.
+///
+/// The keys can be any pointer, such as an `Arc<T>`, a `Box<T>` or a `&T`. Since the map owns its
+/// keys, the value that a key points to can't be freed while it's in the map, so its address
+/// can't be reused for a different value either. The exception is a zero-sized value, which
+/// doesn't take up any memory, so different values can have the same address. See [ByAddress].
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// use std::sync::Arc;
+///
+/// let map = LockFreeIdentityHashMap::<Arc<String>, u32>::new();
+/// let first = Arc::new("key".to_string());
+/// let second = Arc::new("key".to_string());
+/// let guard = lockfreehashmap::pin();
+/// map.insert(ByAddress(first.clone()), 1, &guard);
+/// map.insert(ByAddress(second.clone()), 2, &guard);
+/// assert_eq!(map.len(), 2);
+/// assert_eq!(map.get(ByAddress::from_ref(&first), &guard), Some(&1));
+/// assert_eq!(map.get(ByAddress::from_ref(&second), &guard), Some(&2));
+/// ```
+pub type LockFreeIdentityHashMap<'v, K, V, S = RandomState> =
+    LockFreeHashMap<'v, ByAddress<K>, V, S>;
+
+/// A pointer that is hashed and compared by the address it points to.
+///
+/// Two `ByAddress` are equal if they point to the same value, even if they are different pointers
+/// (e.g. two clones of an `Arc`). Two pointers to equal values at different addresses are not
+/// equal. Only the address is compared, so for unsized values the length or vtable of the pointer
+/// is ignored.
+///
+/// Pointers to zero-sized values, such as `Box<()>` or an empty slice, don't point to any memory,
+/// so they can have the same address even though they point to different values. E.g. every
+/// `Box` of a zero-sized type has the same dangling address, so they're all equal. An `Arc` of a
+/// zero-sized type is still allocated together with its reference counts, so it has its own
+/// address.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// use std::sync::Arc;
+///
+/// let value = Arc::new(5);
+/// assert_eq!(ByAddress(value.clone()), ByAddress(value.clone()));
+/// assert_ne!(ByAddress(value), ByAddress(Arc::new(5)));
+/// ```
+#[derive(Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct ByAddress<P>(pub P);
+
+impl<P> ByAddress<P> {
+    /// Converts a reference to a pointer into a reference to a `ByAddress`, so that a map can be
+    /// searched without cloning the pointer.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// use std::sync::Arc;
+    ///
+    /// let map = LockFreeIdentityHashMap::<Arc<u32>, u32>::new();
+    /// let key = Arc::new(1);
+    /// map.insert(ByAddress(key.clone()), 2, &lockfreehashmap::pin());
+    /// assert!(map.contains_key(ByAddress::from_ref(&key)));
+    /// ```
+    pub fn from_ref(pointer: &P) -> &Self {
+        // `ByAddress<P>` is `repr(transparent)`, so it has the same layout as `P`.
+        unsafe { &*(pointer as *const P as *const Self) }
+    }
+
+    /// Returns the pointer.
+    pub fn into_inner(self) -> P {
+        self.0
+    }
+}
+
+impl<P: Deref> ByAddress<P> {
+    /// Returns the address that the pointer points to.
+    fn address(&self) -> *const () {
+        &*self.0 as *const P::Target as *const ()
+    }
+}
+
+impl<P: Deref> Deref for ByAddress<P> {
+    type Target = P::Target;
+    fn deref(&self) -> &P::Target {
+        &self.0
+    }
+}
+
+impl<P: Deref> Hash for ByAddress<P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+impl<P: Deref> PartialEq for ByAddress<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+impl<P: Deref> Eq for ByAddress<P> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pin;
+    use std::sync::Arc;
+    use test::run_threads;
+
+    #[test]
+    fn test_identity_map_during_resizes() {
+        // Every thread's keys are equal to every other thread's, but at different addresses.
+        let keys = &(0..1024).map(|i| Arc::new(i % 256)).collect::<Vec<_>>();
+        let map = &LockFreeIdentityHashMap::<Arc<u32>, u32>::with_capacity(1);
+        run_threads(4, 256, |indices, guard| {
+            for i in indices {
+                let key = ByAddress(keys[i as usize].clone());
+                assert_eq!(map.insert(key, i / 256, guard), None);
+            }
+        });
+        let guard = pin();
+        assert_eq!(map.len(), 1024);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.get(ByAddress::from_ref(key), &guard), Some(&(i as u32 / 256)));
+        }
+        assert!(!map.contains_key(ByAddress::from_ref(&Arc::new(0))));
+        assert_eq!(map.remove(ByAddress::from_ref(&keys[256]), &guard), Some(&1));
+        assert_eq!(map.get(ByAddress::from_ref(&keys[512]), &guard), Some(&2));
+    }
+
+    #[test]
+    fn test_zero_sized_keys() {
+        let map = LockFreeIdentityHashMap::<Box<()>, u32>::new();
+        let guard = pin();
+        // Every `Box<()>` has the same address, so the second insert replaces the first.
+        assert_eq!(map.insert(ByAddress(Box::new(())), 1, &guard), None);
+        assert_eq!(map.insert(ByAddress(Box::new(())), 2, &guard), Some(&1));
+        assert_eq!(map.len(), 1);
+        // Each `Arc<()>` is its own allocation.
+        let first = Arc::new(());
+        let second = Arc::new(());
+        assert_ne!(ByAddress(first.clone()), ByAddress(second.clone()));
+        let map = LockFreeIdentityHashMap::<Arc<()>, u32>::new();
+        map.insert(ByAddress(first), 1, &guard);
+        map.insert(ByAddress(second), 2, &guard);
+        assert_eq!(map.len(), 2);
+    }
+}
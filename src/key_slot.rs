@@ -0,0 +1,244 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements the key slots of a [MapInner](::map_inner::MapInner), which is generic
+//! over how its keys are stored.
+//!
+//! A key slot goes through the states ∅, K and X of the state diagram in
+//! [map_inner](::map_inner), however it stores them. [BoxedKey] points to an allocated
+//! `KeySlot`, and `InlineKey` in [map_u64](::map_u64) stores a `u64` key in the slot itself.
+
+use crossbeam_epoch::Guard;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use atomic::{AtomicPtr, NotNull, NotNullOwned};
+use map_inner::KeySlot;
+
+/// The state of a key slot when it was loaded.
+#[derive(Clone, Copy, Debug)]
+pub enum LoadedKey<T> {
+    /// The key slot is empty (∅).
+    Empty,
+    /// The key slot was empty when the map was copied into a newer map (X).
+    SeeNewTable,
+    /// The key slot holds a key (K).
+    Key(T),
+}
+
+/// The result of `AtomicKey::put_owned()`, which gives back the key if it wasn't put.
+pub type PutOwned<'g, K, A> = Result<
+    <A as AtomicKey<K>>::Shared<'g>,
+    (LoadedKey<<A as AtomicKey<K>>::Shared<'g>>, <A as AtomicKey<K>>::Owned),
+>;
+
+/// The key slot of a `KVPair`, which can hold a key of type `K`.
+///
+/// Once a key slot holds a key or `SeeNewTable`, it never changes again, except through `take()`.
+pub trait AtomicKey<K>: Sized {
+    /// A key that can be put into an empty key slot.
+    type Owned;
+    /// A key that was loaded from a key slot. It can be put into a key slot of a newer map too.
+    type Shared<'g>: Copy where K: 'g;
+    /// A key as it's returned from the map, e.g. by `MapInner::get_key_value()`.
+    type Ref<'g> where K: 'g;
+
+    /// Creates an empty key slot.
+    fn empty() -> Self;
+
+    /// Creates a key that can be put into a key slot.
+    fn new_key(key: K) -> Self::Owned;
+
+    /// Returns a reference to the key in `key`.
+    fn owned_key(key: &Self::Owned) -> &K;
+
+    /// Returns a reference to the key in `key`.
+    fn shared_key<'a, 'g>(key: &'a Self::Shared<'g>) -> &'a K;
+
+    /// Returns `key` as it's returned from the map.
+    fn key_ref<'g>(key: Self::Shared<'g>) -> Self::Ref<'g>;
+
+    /// Loads the key slot.
+    fn load<'g>(&self, guard: &'g Guard) -> LoadedKey<Self::Shared<'g>>;
+
+    /// Puts `key` into the key slot if it's empty. Otherwise, returns the current key along with
+    /// `key`.
+    fn put_owned<'g>(&self, key: Self::Owned, guard: &'g Guard) -> PutOwned<'g, K, Self>;
+
+    /// Puts `key` into the key slot if it's empty. Otherwise, returns the current key.
+    fn put_shared<'g>(&self, key: Self::Shared<'g>, guard: &'g Guard)
+        -> Result<Self::Shared<'g>, LoadedKey<Self::Shared<'g>>>;
+
+    /// Puts `SeeNewTable` into the key slot if it's empty, which is represented by `sentinel` if
+    /// the key slot needs one. Otherwise, returns the current key.
+    fn put_see_new_table<'g>(&self, sentinel: NotNull<'g, KeySlot<K>>, guard: &'g Guard)
+        -> Result<(), LoadedKey<Self::Shared<'g>>>;
+
+    /// Returns true if `key` is owned by this key slot, in which case a newer map can take
+    /// ownership of it. See `MapInner::put_if_match_in_newer_map()`.
+    fn owns<'g>(&self, key: &Self::Shared<'g>, guard: &'g Guard) -> bool;
+
+    /// Records that the key/value pair of this key slot was copied into the newer map, which now
+    /// owns them.
+    fn tag(&self, guard: &Guard);
+
+    /// Returns true if `tag()` was called.
+    fn is_tagged(&self, guard: &Guard) -> bool;
+
+    /// Returns the full hash of the key in the key slot, or 0 if it isn't stored. See `BoxedKey`.
+    fn stored_hash(&self) -> u64;
+
+    /// Stores the full hash of the key in the key slot, if the key slot stores hashes and the hash
+    /// isn't stored already.
+    fn store_hash(&self, hash: u64);
+
+    /// Takes the key out of the key slot, leaving it empty.
+    ///
+    /// # Unsafe
+    /// This is unsafe for the same reasons as `MapInner::take_at()`.
+    unsafe fn take(&self, guard: &Guard) -> Option<K>;
+
+    /// Frees the key in the key slot, if the key slot owns it. `sentinel` is the one that was given
+    /// to `put_see_new_table()`.
+    ///
+    /// # Unsafe
+    /// This is unsafe because no other thread can access the key slot anymore.
+    unsafe fn drop_key(&mut self, sentinel: &KeySlot<K>);
+}
+
+/// A key slot that points to an allocated `KeySlot`, along with the full hash of the key.
+///
+/// The hash is stored so that probing can skip most keys without comparing them, and so that
+/// keys don't need to be rehashed when they're copied into a newer map. It's 0 until some thread
+/// that knows the hash has stored it, so 0 means that the keys need to be compared.
+///
+/// A key that is copied into a newer map isn't copied itself. Instead, the pointer to it is
+/// tagged, so that this key slot doesn't free it.
+#[derive(Debug)]
+pub struct BoxedKey<K> {
+    key: AtomicPtr<KeySlot<K>>,
+    hash: AtomicU64,
+}
+
+impl<K> BoxedKey<K> {
+    /// Converts a key that was loaded from a key slot to a `LoadedKey`.
+    fn loaded<'g>(key: NotNull<'g, KeySlot<K>>) -> LoadedKey<NotNull<'g, KeySlot<K>>> {
+        match *key.deref() {
+            KeySlot::Key(_) => LoadedKey::Key(key),
+            KeySlot::SeeNewTable => LoadedKey::SeeNewTable,
+        }
+    }
+}
+
+impl<K> AtomicKey<K> for BoxedKey<K> {
+    type Owned = NotNullOwned<KeySlot<K>>;
+    type Shared<'g> = NotNull<'g, KeySlot<K>> where K: 'g;
+    type Ref<'g> = &'g K where K: 'g;
+
+    fn empty() -> Self {
+        BoxedKey { key: AtomicPtr::new(None), hash: AtomicU64::new(0) }
+    }
+
+    fn new_key(key: K) -> Self::Owned {
+        NotNullOwned::new(KeySlot::Key(key))
+    }
+
+    fn owned_key(key: &Self::Owned) -> &K {
+        match **key {
+            KeySlot::Key(ref key) => key,
+            KeySlot::SeeNewTable => unreachable!("`new_key()` creates a `KeySlot::Key`"),
+        }
+    }
+
+    fn shared_key<'a, 'g>(key: &'a Self::Shared<'g>) -> &'a K {
+        Self::key_ref(*key)
+    }
+
+    fn key_ref<'g>(key: Self::Shared<'g>) -> &'g K {
+        match *key.deref() {
+            KeySlot::Key(ref key) => key,
+            KeySlot::SeeNewTable => unreachable!("`LoadedKey::Key` is never `SeeNewTable`"),
+        }
+    }
+
+    fn load<'g>(&self, guard: &'g Guard) -> LoadedKey<Self::Shared<'g>> {
+        match self.key.load(guard).as_option() {
+            Some(key) => Self::loaded(key),
+            None => LoadedKey::Empty,
+        }
+    }
+
+    fn put_owned<'g>(&self, key: Self::Owned, guard: &'g Guard) -> PutOwned<'g, K, Self> {
+        self.key.compare_null_and_set_owned(key, guard)
+            .map_err(|(current, key)| (Self::loaded(current), key))
+    }
+
+    fn put_shared<'g>(&self, key: Self::Shared<'g>, guard: &'g Guard)
+        -> Result<Self::Shared<'g>, LoadedKey<Self::Shared<'g>>>
+    {
+        self.key.compare_null_and_set(key, guard)
+            .map_err(|(current, _)| Self::loaded(current))
+    }
+
+    fn put_see_new_table<'g>(&self, sentinel: NotNull<'g, KeySlot<K>>, guard: &'g Guard)
+        -> Result<(), LoadedKey<Self::Shared<'g>>>
+    {
+        self.key.compare_null_and_set(sentinel, guard)
+            .map(|_| ())
+            .map_err(|(current, _)| Self::loaded(current))
+    }
+
+    fn owns<'g>(&self, key: &Self::Shared<'g>, guard: &'g Guard) -> bool {
+        self.key.load(guard).as_shared().as_raw() == key.as_shared().as_raw()
+    }
+
+    fn tag(&self, guard: &Guard) {
+        self.key.tag(guard);
+    }
+
+    fn is_tagged(&self, guard: &Guard) -> bool {
+        self.key.is_tagged(guard)
+    }
+
+    fn stored_hash(&self) -> u64 {
+        // Hashes don't publish any other data, and every thread stores the same hash, so they
+        // don't need to synchronize with anything.
+        self.hash.load(Ordering::Relaxed)
+    }
+
+    fn store_hash(&self, hash: u64) {
+        if self.hash.load(Ordering::Relaxed) == 0 {
+            self.hash.store(hash, Ordering::Relaxed);
+        }
+    }
+
+    unsafe fn take(&self, guard: &Guard) -> Option<K> {
+        match *self.key.take(guard)? {
+            KeySlot::Key(key) => Some(key),
+            KeySlot::SeeNewTable => unreachable!("A map without a newer map has no `SeeNewTable`"),
+        }
+    }
+
+    unsafe fn drop_key(&mut self, sentinel: &KeySlot<K>) {
+        let guard = ::crossbeam_epoch::unprotected();
+        // Tagged keys were copied into a newer map, which now owns them.
+        let owned = !self.key.is_tagged(guard) && self.key.load(guard).as_option()
+            .is_some_and(|key| !ptr::eq(key.deref(), sentinel));
+        if owned {
+            self.key.try_drop(guard);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the types that decide how a map is laid out in memory, i.e. which data
+//! shares a cache line with which.
+
+use std::ops::Deref;
+
+/// A value on its own cache line, so that threads writing to it don't slow down threads reading
+/// or writing the data next to it ("false sharing").
+#[derive(Debug, Default)]
+#[repr(align(64))]
+pub(crate) struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// How the key slots of a map are laid out in memory.
+///
+/// Every key slot holds a pointer to the key, the hash of the key and a pointer to the value,
+/// which are next to each other so that a lookup usually only reads one cache line per probe.
+/// This decides whether a key slot can be split over two cache lines.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+///     .table_layout(TableLayout::Aligned)
+///     .build();
+/// map.insert(1, 2, &lockfreehashmap::pin());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TableLayout {
+    /// Key slots are 24 bytes on 64-bit targets, and some of them are split over two cache lines.
+    /// This is the default, since it uses the least memory.
+    #[default]
+    Packed,
+    /// Key slots are padded to 32 bytes, so that no key slot is split over two cache lines and a
+    /// probe never has to read two of them. This uses a third more memory than `Packed`.
+    Aligned,
+}
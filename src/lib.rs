@@ -38,24 +38,54 @@
 extern crate crossbeam_epoch;
 extern crate crossbeam_utils as crossbeam;
 
-use std::borrow::Borrow;
+use std::cell::Cell;
 use std::collections::hash_map::RandomState;
 use std::fmt;
 use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+use std::mem;
 
 mod atomic;
+mod builder;
+mod counter;
+mod entry;
+mod equivalent;
+mod identity;
+mod key_slot;
+mod layout;
 mod map_inner;
+mod map_u64;
+#[cfg(all(test, loom))]
+mod model;
+mod resize;
+mod set;
 
 /// Re-export `crossbeam::epoch::pin()` and its return type for convenience.
 pub use crossbeam_epoch::{pin, Guard};
 /// Re-export `crossbeam::scope()` and its return type for convenience.
 pub use crossbeam::scoped::{scope, Scope};
 
-use atomic::AtomicBox;
-use map_inner::{KeyCompare, KeySlot, MapInner, Match, PutValue, ValueSlot};
+use atomic::{AtomicBox, NotNullOwned};
+use counter::StripedCounter;
+use key_slot::BoxedKey;
+use map_inner::{KeyCompare, KeySlot, MapInner, Match, PairIter, PutValue, ValueSlot};
 
+pub use builder::LockFreeHashMapBuilder;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use equivalent::Equivalent;
+pub use identity::{ByAddress, LockFreeIdentityHashMap};
+pub use layout::TableLayout;
+pub use map_u64::{IterU64, LockFreeHashMapU64};
+pub use resize::{DefaultResizePolicy, ResizeContention, ResizePolicy, ResizeStats};
+pub use set::{LockFreeHashSet, SetIter};
+
+/// The default number of key slots that a thread copies at a time when a map is resized. See
+/// `LockFreeHashMapBuilder::copy_chunk_size()`.
 pub const COPY_CHUNK_SIZE: usize = 32;
 
+/// The result of `LockFreeHashMap::put_if_match_shared()`.
+type PutSharedResult<'guard, V> = Result<(&'guard V, Option<&'guard V>), (V, Option<&'guard V>)>;
+
 pub struct LockFreeHashMap<'v, K, V: 'v, S = RandomState> {
     /// Points to the newest map (after it's been fully resized). Always non-null.
     inner: AtomicBox<MapInner<'v,K,V,S>>,
@@ -114,6 +144,12 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
 
     /// Returns the number of elements in the map.
     ///
+    /// The count is split over several counters, so that threads inserting and removing at the
+    /// same time don't contend on a single counter. This sums all of them, which is slower than
+    /// `len_estimate()` on machines with many CPUs. If the map is being resized, then this sums
+    /// the counts of the map and its newer maps, without waiting for the resize to finish. A
+    /// key/value pair that is copied into a newer map while they are summed can be counted twice.
+    ///
     /// # Examples
     /// ```
     /// # use lockfreehashmap::*;
@@ -127,7 +163,28 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// ```
     pub fn len(&self) -> usize {
         let guard = pin();
-        self.load_inner(&guard).len()
+        MapInner::sum_over_newer_maps(&self.inner, StripedCounter::sum, &guard)
+    }
+
+    /// Returns an estimate of the number of elements in the map, which is faster than `len()`.
+    ///
+    /// On machines with many CPUs, the estimate is only summed about once a millisecond, and each
+    /// thread only checks the time every few calls, so it can be out of date. Otherwise, this is
+    /// the same as `len()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<u32, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert!(map.len_estimate() <= 100);
+    /// ```
+    pub fn len_estimate(&self) -> usize {
+        let guard = pin();
+        MapInner::sum_over_newer_maps(&self.inner, StripedCounter::estimate, &guard)
     }
 
     /// Clears the entire map.
@@ -172,15 +229,124 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// ```
     pub fn clear_with_capacity(&self, capacity: usize) {
         let guard = pin();
-        let hasher = self.load_inner(&guard).clone_hasher();
-        let newer_map = MapInner::with_capacity_and_hasher(capacity, hasher);
+        let newer_map = self.load_inner(&guard).with_capacity_from(capacity);
         self.inner.replace(newer_map);
     }
 
+    /// Shrinks the capacity of the map as much as possible, while keeping the map at most half
+    /// full. The live key/value pairs are copied into a smaller map, whose effects are visible to
+    /// all threads, in the same way as when the map grows.
+    ///
+    /// If another thread resizes the map at the same time, then this might not shrink the map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<u32, u32>::with_capacity(1024);
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..10 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// map.shrink_to_fit();
+    /// assert_eq!(map.capacity(), 32);
+    /// assert_eq!(map.len(), 10);
+    /// assert!((0..10).all(|i| map.get(&i, &guard) == Some(&i)));
+    /// ```
+    pub fn shrink_to_fit(&self) {
+        self.shrink_to(0);
+    }
+
+    /// Shrinks the capacity of the map to at least `capacity`, while keeping the map at most half
+    /// full. Like `LockFreeHashMap::with_capacity()`, this uses the next power of two if
+    /// `capacity` is not a power of two. The map isn't changed if its capacity is already less
+    /// than or equal to that.
+    ///
+    /// If another thread resizes the map at the same time, then this might not shrink the map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<u32, u32>::with_capacity(1024);
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..10 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// map.shrink_to(100);
+    /// assert_eq!(map.capacity(), 128);
+    /// map.shrink_to(1);
+    /// assert_eq!(map.capacity(), 32);
+    /// map.shrink_to(1000);
+    /// assert_eq!(map.capacity(), 32);
+    /// ```
+    pub fn shrink_to(&self, capacity: usize) {
+        let guard = pin();
+        MapInner::load_newest(&self.inner, &guard).shrink_to(capacity, &self.inner, &guard);
+    }
+
+    /// Reserves capacity for at least `additional` more key/value pairs, so that inserting them
+    /// doesn't resize the map (unless other threads insert too). The map is kept at most half
+    /// full, so this does nothing if the map already has at least twice as much capacity.
+    ///
+    /// Otherwise, the key/value pairs are copied into a bigger map in one go, whose effects are
+    /// visible to all threads, in the same way as when the map is resized by an insert. If
+    /// another thread resizes the map at the same time, then this helps finish that resize first.
+    ///
+    /// # Panics
+    /// Panics if the new capacity overflows `usize`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<u32, u32>::new();
+    /// map.reserve(1000);
+    /// assert_eq!(map.capacity(), 2048);
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..1000 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.capacity(), 2048);
+    /// ```
+    pub fn reserve(&self, additional: usize) {
+        let guard = pin();
+        while !MapInner::load_newest(&self.inner, &guard).reserve(additional, &self.inner, &guard) {}
+        // Drive the copy to completion, even if other threads are still copying their chunks.
+        MapInner::load_newest(&self.inner, &guard);
+    }
+
+    /// Clears the map, returning all key/value pairs as an iterator.
+    ///
+    /// Like `clear()`, the map is replaced with an empty map of the same capacity and settings.
+    /// This takes `&mut self`, so no other thread can access the old map, and its pairs can be
+    /// moved out right away, like `into_iter()`. Use `clear()` instead to clear a map that other
+    /// threads are using.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let mut map = LockFreeHashMap::<i32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(1, "one".to_string(), &guard);
+    /// map.insert(2, "two".to_string(), &guard);
+    /// drop(guard);
+    ///
+    /// let mut pairs = map.drain().collect::<Vec<_>>();
+    /// pairs.sort();
+    /// assert_eq!(pairs, vec![(1, "one".to_string()), (2, "two".to_string())]);
+    /// assert_eq!(map.len(), 0);
+    /// ```
+    pub fn drain(&mut self) -> IntoIter<'v, K, V, S> {
+        let empty_map = {
+            let guard = pin();
+            let inner = self.load_inner(&guard);
+            LockFreeHashMap { inner: AtomicBox::new(inner.with_capacity_from(inner.capacity())) }
+        };
+        mem::replace(self, empty_map).into_iter()
+    }
+
     /// Returns true if the map contains a value for the specified key.
     ///
-    /// The key may be any borrowed form of the map's key type, but Hash and Eq on the borrowed
-    /// form must match those for the key type.
+    /// The key may be any type that is [Equivalent] to the map's key type, such as a borrowed form
+    /// of it, but its hash must match the hash of the key.
     ///
     /// # Examples
     /// ```
@@ -194,16 +360,15 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// assert!(!map.contains_key(&3));
     /// ```
     pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
-        where K: Borrow<Q>,
-              Q: Hash + Eq + PartialEq<K>,
+        where Q: Hash + Equivalent<K>,
     {
         let guard = pin();
         self.get(key, &guard).is_some()
     }
 
-    /// Returns a reference to the value corresponding to the key. The key may be any borrowed
-    /// form of the map's key type, but Hash and Eq on the borrowed form must match those for the
-    /// key type.
+    /// Returns a reference to the value corresponding to the key. The key may be any type that is
+    /// [Equivalent] to the map's key type, such as a borrowed form of it, but its hash must match
+    /// the hash of the key.
     ///
     /// # Examples
     /// ```
@@ -215,8 +380,7 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// assert_eq!(map.get(&1, &guard), Some(&15));
     /// ```
     pub fn get<'s: 'guard, Q: ?Sized>(&'s self, key: &Q, guard: &'guard Guard) -> Option<&'guard V>
-        where K: Borrow<Q>,
-              Q: Hash + Eq + PartialEq<K>,
+        where Q: Hash + Equivalent<K>,
     {
         return self.load_inner(guard).get(key, &self.inner, guard);
     }
@@ -252,6 +416,87 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
         return ValueSlot::as_inner(value_slot);
     }
 
+    /// Inserts a key-value pair into the map, but only if the map did not have this key present.
+    /// If the key-value pair was inserted, `Ok` is returned with a reference to the inserted value.
+    /// Otherwise, `key` and `value` are dropped and `Err` is returned with the current value.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, i32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.insert_if_absent(1, 10, &guard), Ok(&10));
+    /// assert_eq!(map.insert_if_absent(1, 20, &guard), Err(&10));
+    /// map.remove(&1, &guard);
+    /// assert_eq!(map.insert_if_absent(1, 30, &guard), Ok(&30));
+    /// ```
+    pub fn insert_if_absent<'s: 'guard>(&'s self, key: K, value: V, guard: &'guard Guard)
+        -> Result<&'guard V, &'guard V>
+    {
+        match self.put_if_match_shared(KeyCompare::new(key), value, Match::NoValue, guard) {
+            Ok((inserted, _)) => Ok(inserted),
+            Err((_value, current)) => Err(current.expect("`Match::NoValue` only fails on a value")),
+        }
+    }
+
+    /// Inserts a key-value pair into the map, but only if the map did not have this key present.
+    /// If the key-value pair was inserted, `Ok` is returned with a reference to the inserted value.
+    /// Otherwise, ownership of `key` and `value` is given back in `Err`, along with the current
+    /// value.
+    ///
+    /// Another thread can insert a value for the same key at the same time as this thread inserts
+    /// `key` into the map itself. Only in that case, `key` is owned by the map and a clone of it is
+    /// returned instead.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<String, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// let (one, two) = ("one".to_string(), "two".to_string());
+    /// assert_eq!(map.try_insert("key".to_string(), one.clone(), &guard), Ok(&one));
+    /// let (key, value, current) = map.try_insert("key".to_string(), two.clone(), &guard)
+    ///     .unwrap_err();
+    /// assert_eq!((key, value, current), ("key".to_string(), two, &one));
+    /// ```
+    pub fn try_insert<'s: 'guard>(&'s self, key: K, value: V, guard: &'guard Guard)
+        -> Result<&'guard V, (K, V, &'guard V)>
+        where K: Clone,
+    {
+        // This is safe because `key_slot` is either inserted into the map, which is then
+        // responsible for dropping it, or otherwise ownership is taken back below.
+        let key_slot = unsafe { NotNullOwned::new(KeySlot::Key(key)).into_not_null() };
+        let inserted = Cell::new(false);
+        let result = self.put_if_match_shared(
+            KeyCompare::<K, K>::Reclaimable(key_slot, &inserted),
+            value,
+            Match::NoValue,
+            guard
+        );
+        // If `key_slot` wasn't inserted, then no other thread has seen it and so we can take back
+        // ownership of it.
+        let reclaim_key = || match *unsafe { key_slot.into_box() } {
+            KeySlot::Key(key) => key,
+            KeySlot::SeeNewTable => unreachable!("`key_slot` was created as a `KeySlot::Key`"),
+        };
+        match result {
+            Ok((value, _)) => {
+                if !inserted.get() {
+                    // An equal key was already in the map, which is used instead.
+                    drop(reclaim_key());
+                }
+                Ok(value)
+            },
+            Err((value, current)) => {
+                let key = match *key_slot.deref() {
+                    KeySlot::Key(ref key) if inserted.get() => key.clone(),
+                    _ => reclaim_key(),
+                };
+                Err((key, value, current.expect("`Match::NoValue` only fails on a value")))
+            },
+        }
+    }
+
     /// Inserts a key-value pair into the map, but only if there is already an existing value that
     /// corresponds to the key in the map. If the map did not have this key present, None is
     /// returned. If the map did have this key present, the value is updated, and the old value is
@@ -270,8 +515,7 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// ```
     pub fn replace<'s: 'guard, Q: ?Sized>(&'s self, key: &Q, value: V, guard: &'guard Guard)
         -> Option<&'guard V>
-        where K: Borrow<Q>,
-              Q: Hash + Eq + PartialEq<K>,
+        where Q: Hash + Equivalent<K>,
     {
         let value_slot: Option<&ValueSlot<V>> = self.load_inner(guard).put_if_match(
             KeyCompare::OnlyCompare(key),
@@ -283,9 +527,181 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
         return ValueSlot::as_inner(value_slot);
     }
 
+    /// Replaces the value corresponding to the key with `new`, but only if the current value is
+    /// `==` to `expected`. The key may be any type that is [Equivalent] to the map's key type, such
+    /// as a borrowed form of it, but its hash must match the hash of the key.
+    ///
+    /// If the value was replaced, `Ok` is returned with the previous value. Otherwise, `new` is
+    /// dropped and `Err` is returned with the current value, or `None` if the map did not have
+    /// this key present.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// let one = "one".to_string();
+    /// assert_eq!(map.compare_and_swap(&1, &one, "two".to_string(), &guard), Err(None));
+    /// map.insert(1, "one".to_string(), &guard);
+    /// assert_eq!(map.compare_and_swap(&1, &one, "two".to_string(), &guard), Ok(&one));
+    /// assert_eq!(
+    ///     map.compare_and_swap(&1, &one, "three".to_string(), &guard),
+    ///     Err(Some(&"two".to_string()))
+    /// );
+    /// assert_eq!(map.get(&1, &guard), Some(&"two".to_string()));
+    /// ```
+    pub fn compare_and_swap<'s: 'guard, Q>(
+        &'s self,
+        key: &Q,
+        expected: &V,
+        new: V,
+        guard: &'guard Guard
+    ) -> Result<&'guard V, Option<&'guard V>>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let matcher = Match::Value(expected);
+        let value_slot: Option<&ValueSlot<V>> = self.load_inner(guard).put_if_match(
+            KeyCompare::OnlyCompare(key),
+            PutValue::new(new),
+            matcher,
+            &self.inner,
+            guard
+        );
+        match matcher.matches(value_slot) {
+            true => Ok(ValueSlot::as_inner(value_slot).expect("matched an existing value")),
+            false => Err(ValueSlot::as_inner(value_slot)),
+        }
+    }
+
+    /// Atomically replaces the value corresponding to the key with the result of `f`, returning the
+    /// new value. If the map did not have this key present, `f` is not called and None is returned.
+    /// The key may be any type that is [Equivalent] to the map's key type, such as a borrowed form
+    /// of it, but its hash must match the hash of the key.
+    ///
+    /// This is equivalent to `compute_if_present()` with a closure that never removes the key. See
+    /// `compute()` for details on how `f` is called when other threads change the same key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.update(&"counter", |v| v + 1, &guard), None);
+    /// map.insert("counter", 0, &guard);
+    /// assert_eq!(map.update(&"counter", |v| v + 1, &guard), Some(&1));
+    /// assert_eq!(map.update(&"counter", |v| v + 1, &guard), Some(&2));
+    /// ```
+    pub fn update<'s: 'guard, Q, F>(&'s self, key: &Q, mut f: F, guard: &'guard Guard)
+        -> Option<&'guard V>
+        where Q: Hash + Equivalent<K> + ?Sized,
+              F: FnMut(&V) -> V,
+    {
+        self.compute_if_present(key, |v| Some(f(v)), guard)
+    }
+
+    /// Atomically computes a new value for the key from its current value, returning the new value.
+    ///
+    /// `f` is given the current value corresponding to the key, or None if the map did not have
+    /// this key present. If `f` returns None, then the key is removed (or stays absent).
+    /// Otherwise, the value it returns is inserted. The new value is only published if the
+    /// current value has not changed since it was given to `f`, so concurrent updates to the same
+    /// key are never lost.
+    ///
+    /// # Closure Re-execution
+    /// Under contention, another thread may change the value after `f` is called but before its
+    /// result is published. In that case, the result is dropped and `f` is called again with the
+    /// newer value. `f` can therefore be called more than once and should not have side effects.
+    /// Values are compared with `==`, so `V`'s `PartialEq` implementation must be reflexive, or
+    /// else this can loop forever (e.g. with `f64::NAN`).
+    ///
+    /// `key` is only cloned when a value needs to be inserted for a key that isn't in the map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<String, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// let key = "counter".to_string();
+    /// let increment = |v: Option<&u32>| Some(v.map_or(1, |v| v + 1));
+    /// assert_eq!(map.compute(key.clone(), increment, &guard), Some(&1));
+    /// assert_eq!(map.compute(key.clone(), increment, &guard), Some(&2));
+    /// assert_eq!(map.compute(key.clone(), |_| None, &guard), None);
+    /// assert!(!map.contains_key(&key));
+    /// ```
+    pub fn compute<'s: 'guard, F>(&'s self, key: K, mut f: F, guard: &'guard Guard)
+        -> Option<&'guard V>
+        where K: Clone,
+              F: FnMut(Option<&V>) -> Option<V>,
+    {
+        loop {
+            let current = self.get(&key, guard);
+            let key_compare = match current {
+                Some(_) => KeyCompare::OnlyCompare(&key),
+                None => KeyCompare::new(key.clone()),
+            };
+            if let Some(result) = self.try_compute(key_compare, current, f(current), guard) {
+                return result;
+            }
+        }
+    }
+
+    /// Atomically computes a new value for the key from its current value, but only if the map has
+    /// this key present, returning the new value. If `f` returns None, then the key is removed. The
+    /// key may be any type that is [Equivalent] to the map's key type, such as a borrowed form of
+    /// it, but its hash must match the hash of the key.
+    ///
+    /// See `compute()` for details on how `f` is called when other threads change the same key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, i32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.compute_if_present(&1, |v| Some(v * 10), &guard), None);
+    /// map.insert(1, 1, &guard);
+    /// assert_eq!(map.compute_if_present(&1, |v| Some(v * 10), &guard), Some(&10));
+    /// assert_eq!(map.compute_if_present(&1, |_| None, &guard), None);
+    /// assert!(!map.contains_key(&1));
+    /// ```
+    pub fn compute_if_present<'s: 'guard, Q, F>(&'s self, key: &Q, mut f: F, guard: &'guard Guard)
+        -> Option<&'guard V>
+        where Q: Hash + Equivalent<K> + ?Sized,
+              F: FnMut(&V) -> Option<V>,
+    {
+        loop {
+            let current = self.get(key, guard)?;
+            let key_compare = KeyCompare::OnlyCompare(key);
+            if let Some(result) = self.try_compute(key_compare, Some(current), f(current), guard) {
+                return result;
+            }
+        }
+    }
+
+    /// Returns the value corresponding to the key, inserting the result of `f` first if the map
+    /// did not have this key present.
+    ///
+    /// `f` is called at most once. If another thread inserts a value for this key before the
+    /// result of `f` is published, then that result is dropped and the other thread's value is
+    /// returned instead. This is equivalent to `map.entry(key, guard).or_insert_with(f)`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.compute_if_absent(1, || "one".to_string(), &guard), "one");
+    /// assert_eq!(map.compute_if_absent(1, || "uno".to_string(), &guard), "one");
+    /// ```
+    pub fn compute_if_absent<'s: 'guard, F>(&'s self, key: K, f: F, guard: &'guard Guard)
+        -> &'guard V
+        where F: FnOnce() -> V,
+    {
+        self.entry(key, guard).or_insert_with(f)
+    }
+
     /// Removes a key from the map, returning the value at the key if the key was previously in the
-    /// map. The key may be any borrowed form of the map's key type, but Hash and Eq on the
-    /// borrowed form must match those for the key type.
+    /// map. The key may be any type that is [Equivalent] to the map's key type, such as a borrowed
+    /// form of it, but its hash must match the hash of the key.
     ///
     /// # Examples
     /// ```
@@ -298,8 +714,7 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// ```
     pub fn remove<'s: 'guard, Q: ?Sized>(&'s self, key: &Q, guard: &'guard Guard)
         -> Option<&'guard V>
-        where K: Borrow<Q>,
-              Q: Hash + Eq + PartialEq<K>,
+        where Q: Hash + Equivalent<K>,
     {
         let value_slot: Option<&ValueSlot<V>> = self.load_inner(guard).put_if_match(
             KeyCompare::OnlyCompare(key),
@@ -311,6 +726,153 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
         return ValueSlot::as_inner(value_slot);
     }
 
+    /// Removes a key from the map, but only if its current value satisfies `predicate`, returning
+    /// the removed value. The key may be any type that is [Equivalent] to the map's key type, such
+    /// as a borrowed form of it, but its hash must match the hash of the key.
+    ///
+    /// If another thread changes the value after `predicate` is called but before the key is
+    /// removed, `predicate` is called again with the newer value. See `compute()` for details.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, i32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(1, 10, &guard);
+    /// assert_eq!(map.remove_if(&1, |v| *v > 10, &guard), None);
+    /// assert_eq!(map.remove_if(&1, |v| *v == 10, &guard), Some(&10));
+    /// assert!(!map.contains_key(&1));
+    /// ```
+    pub fn remove_if<'s: 'guard, Q, F>(&'s self, key: &Q, mut predicate: F, guard: &'guard Guard)
+        -> Option<&'guard V>
+        where Q: Hash + Equivalent<K> + ?Sized,
+              F: FnMut(&V) -> bool,
+    {
+        let mut current = self.get(key, guard)?;
+        loop {
+            if !predicate(current) {
+                return None;
+            }
+            match self.try_remove_value(key, current, guard) {
+                Ok(removed) => return Some(removed),
+                Err(newer) => current = newer?,
+            }
+        }
+    }
+
+    /// Removes a key from the map, but only if its current value is `==` to `expected`, returning
+    /// the removed value. The key may be any type that is [Equivalent] to the map's key type, such
+    /// as a borrowed form of it, but its hash must match the hash of the key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u64>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert("session", 1234, &guard);
+    /// assert_eq!(map.remove_if_eq(&"session", &5678, &guard), None);
+    /// assert_eq!(map.remove_if_eq(&"session", &1234, &guard), Some(&1234));
+    /// assert!(!map.contains_key(&"session"));
+    /// ```
+    pub fn remove_if_eq<'s: 'guard, Q>(&'s self, key: &Q, expected: &V, guard: &'guard Guard)
+        -> Option<&'guard V>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.try_remove_value(key, expected, guard).ok()
+    }
+
+    /// Removes a key from the map, returning the stored key and its value if the key was previously
+    /// in the map. The key may be any type that is [Equivalent] to the map's key type, such as a
+    /// borrowed form of it, but its hash must match the hash of the key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<String, i32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.remove_entry("one", &guard), None);
+    /// map.insert("one".to_string(), 1, &guard);
+    /// assert_eq!(map.remove_entry("one", &guard), Some((&"one".to_string(), &1)));
+    /// assert!(!map.contains_key("one"));
+    /// ```
+    pub fn remove_entry<'s: 'guard, Q>(&'s self, key: &Q, guard: &'guard Guard)
+        -> Option<(&'guard K, &'guard V)>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        loop {
+            let (stored_key, current) = self.load_inner(guard)
+                .get_key_value(key, &self.inner, guard)?;
+            if let Ok(removed) = self.try_remove_value(key, current, guard) {
+                return Some((stored_key, removed));
+            }
+        }
+    }
+
+    /// Retains only the key/value pairs specified by the predicate, returning the number of
+    /// key/value pairs that were removed. In other words, removes all pairs `(k, v)` such that
+    /// `f(&k, &v)` returns false.
+    ///
+    /// The map is walked at one point in time, like `iter()`. Any key/value pairs inserted
+    /// afterwards, or moved into a newer table by a concurrent resize, may or may not be visited.
+    /// If another thread changes a value after `f` is called but before the pair is removed, `f`
+    /// is called again with the newer value.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, i32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..8 {
+    ///     map.insert(i, i * 10, &guard);
+    /// }
+    /// assert_eq!(map.retain(|&k, _| k % 2 == 0), 4);
+    /// let mut keys = map.keys(&guard).cloned().collect::<Vec<_>>();
+    /// keys.sort();
+    /// assert_eq!(keys, vec![0, 2, 4, 6]);
+    /// ```
+    pub fn retain<F>(&self, mut f: F) -> usize
+        where F: FnMut(&K, &V) -> bool,
+    {
+        let guard = pin();
+        let mut removed = 0;
+        for (key, mut value) in self.iter(&guard) {
+            while !f(key, value) {
+                match self.try_remove_value(key, value, &guard) {
+                    Ok(_) => {
+                        removed += 1;
+                        break;
+                    },
+                    Err(Some(current)) => value = current,
+                    Err(None) => break,
+                }
+            }
+        }
+        removed
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    ///
+    /// Whether the entry is occupied or vacant is decided at one point in time. Other threads can
+    /// still insert or remove the key afterwards, which the methods on [Entry] take into account.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<&str, u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.entry("poneyland", &guard).or_insert(3), &3);
+    /// assert_eq!(map.entry("poneyland", &guard).or_insert(10), &3);
+    /// let value = map.entry("poneyland", &guard).and_modify(|v| v + 1).or_insert(0);
+    /// assert_eq!(value, &4);
+    /// assert_eq!(map.get(&"poneyland", &guard), Some(&4));
+    /// ```
+    pub fn entry<'s: 'guard>(&'s self, key: K, guard: &'guard Guard) -> Entry<'guard, 'v, K, V, S> {
+        match self.get(&key, guard) {
+            Some(value) => Entry::Occupied(OccupiedEntry::new(self, key, value, guard)),
+            None => Entry::Vacant(VacantEntry::new(self, key, guard)),
+        }
+    }
+
     /// Returns an iterator over the keys in the map at one point in time. Any keys
     /// inserted or removed after this point in time may or may not be returned by this iterator.
     ///
@@ -335,106 +897,494 @@ impl<'guard, 'v: 'guard, K, V, S> LockFreeHashMap<'v,K,V,S>
     /// keys.sort();
     /// assert_eq!(vec![4, 8, 15, 23, 42], keys);
     /// ```
-    pub fn keys(&self, guard: &'guard Guard) -> Keys<'guard, 'v, K, V, S> {
-        let mut inner = self.inner.load(guard);
-        while let Some(newer_map) = inner.newer_map.load(guard).as_option() {
-            inner.help_copy(newer_map, true, &self.inner, guard);
-            inner = self.inner.load(guard);
-        }
-        Keys {
-            position: 0,
-            guard: guard,
-            map: inner.deref(),
-        }
+    pub fn keys<'s: 'guard>(&'s self, guard: &'guard Guard) -> Keys<'guard, 'v, K, V, S> {
+        Keys { pairs: PairIter::new(&self.inner, guard) }
     }
-}
-
-impl<'guard, 'v: 'guard, K: Hash + Eq + 'guard, V: PartialEq> LockFreeHashMap<'v,K,V> {
 
-    /// Creates a new `LockFreeHashMap`.
+    /// Returns an iterator over the values in the map at one point in time. Any values
+    /// inserted or removed after this point in time may or may not be returned by this iterator.
     ///
     /// # Examples
     /// ```
-    /// # #![allow(unused_variables)]
-    /// # use lockfreehashmap::LockFreeHashMap;
-    /// let map = LockFreeHashMap::<u32, String>::new();
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(4, "Four".to_string(), &guard);
+    /// map.insert(8, "Eight".to_string(), &guard);
+    /// map.insert(15, "Fifteen".to_string(), &guard);
+    ///
+    /// let mut values = map.values(&guard).cloned().collect::<Vec<_>>();
+    /// values.sort();
+    /// assert_eq!(vec!["Eight", "Fifteen", "Four"], values);
+    ///
+    /// map.remove(&8, &guard);
+    /// let mut values = map.values(&guard).cloned().collect::<Vec<_>>();
+    /// values.sort();
+    /// assert_eq!(vec!["Fifteen", "Four"], values);
     /// ```
-    pub fn new() -> Self {
-        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    pub fn values<'s: 'guard>(&'s self, guard: &'guard Guard) -> Values<'guard, 'v, K, V, S> {
+        Values { pairs: PairIter::new(&self.inner, guard) }
     }
 
-    /// Creates a new `LockFreeHashMap` of a given size. Uses the next power of two if size is not
-    /// a power of two.
+    /// Returns an iterator over the key/value pairs in the map at one point in time. Any
+    /// key/value pairs inserted or removed after this point in time may or may not be returned by
+    /// this iterator.
     ///
     /// # Examples
     /// ```
-    /// # use lockfreehashmap::LockFreeHashMap;
-    /// let map = LockFreeHashMap::<u32, String>::with_capacity(12);
-    /// assert_eq!(map.capacity(), 12usize.next_power_of_two());
-    /// assert_eq!(map.capacity(), 16);
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(4, "Four".to_string(), &guard);
+    /// map.insert(8, "Eight".to_string(), &guard);
+    ///
+    /// let mut pairs = map.iter(&guard).collect::<Vec<_>>();
+    /// pairs.sort();
+    /// assert_eq!(vec![(&4, &"Four".to_string()), (&8, &"Eight".to_string())], pairs);
     /// ```
-    pub fn with_capacity(size: usize) -> Self {
-        LockFreeHashMap { inner: AtomicBox::new(MapInner::with_capacity(size)) }
+    pub fn iter<'s: 'guard>(&'s self, guard: &'guard Guard) -> Iter<'guard, 'v, K, V, S> {
+        Iter { pairs: PairIter::new(&self.inner, guard) }
     }
-}
 
+    /// Private helper method that calls `MapInner::put_if_match()` with `value`, but keeps a
+    /// pointer to it so that a reference to it can be returned if it was inserted.
+    ///
+    /// Returns `Ok((inserted, previous))` if `value` was inserted into the map. Otherwise, returns
+    /// `Err((value, current))`, giving back ownership of `value` along with the current value that
+    /// didn't match `matcher`.
+    ///
+    /// Note that `put_if_match()` returns `None` without inserting anything if `key` is only
+    /// compared and isn't in the map, so `KeyCompare::OnlyCompare` can't be used with a `matcher`
+    /// that matches a missing key.
+    pub(crate) fn put_if_match_shared<'s: 'guard, Q>(
+        &'s self,
+        key: KeyCompare<'guard, '_, K, Q>,
+        value: V,
+        matcher: Match<V>,
+        guard: &'guard Guard,
+    ) -> PutSharedResult<'guard, V>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        // This is safe because `shared` is either published by `put_if_match()`, in which case the
+        // map is responsible for dropping it, or otherwise ownership is taken back below.
+        let shared = unsafe { NotNullOwned::new(ValueSlot::Value(value)).into_not_null() };
+        let previous: Option<&ValueSlot<V>> = self.load_inner(guard).put_if_match(
+            key,
+            PutValue::Shared(shared),
+            matcher,
+            &self.inner,
+            guard
+        );
+        if matcher.matches(previous) {
+            let inserted = ValueSlot::as_inner(Some(shared.deref()))
+                .expect("`shared` was created as a `ValueSlot::Value`");
+            Ok((inserted, ValueSlot::as_inner(previous)))
+        } else {
+            // No other thread has seen `shared`, so we can take back ownership of it.
+            match *unsafe { shared.into_box() } {
+                ValueSlot::Value(value) => Err((value, ValueSlot::as_inner(previous))),
+                _ => unreachable!("`shared` was created as a `ValueSlot::Value`"),
+            }
+        }
+    }
 
-impl<'v, K, V, S> Drop for LockFreeHashMap<'v, K, V, S> {
-    fn drop(&mut self) {
-        let guard = pin();
+    /// Private helper method for the `compute()` family of methods. Tries to replace `current`
+    /// with `new` once, where None means that there is no value for the key.
+    ///
+    /// Returns `Some` with the resulting value if the map was changed (or nothing needed to be
+    /// changed), or None if `current` was no longer the current value and the caller should retry.
+    /// When `current` is None, `key` must be a `KeyCompare::Owned` so that it can be inserted.
+    fn try_compute<'s: 'guard, Q>(
+        &'s self,
+        key: KeyCompare<'guard, '_, K, Q>,
+        current: Option<&V>,
+        new: Option<V>,
+        guard: &'guard Guard,
+    ) -> Option<Option<&'guard V>>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let matcher = match current {
+            Some(current) => Match::Value(current),
+            None => Match::NoValue,
+        };
+        match new {
+            Some(new) => match self.put_if_match_shared(key, new, matcher, guard) {
+                Ok((inserted, _)) => Some(Some(inserted)),
+                Err(_) => None,
+            },
+            None if current.is_none() => Some(None),
+            None => {
+                let value_slot: Option<&ValueSlot<V>> = self.load_inner(guard).put_if_match(
+                    key,
+                    PutValue::new_tombstone(),
+                    matcher,
+                    &self.inner,
+                    guard
+                );
+                match matcher.matches(value_slot) {
+                    true => Some(None),
+                    false => None,
+                }
+            },
+        }
+    }
+
+    /// Private helper method that removes a key from the map, but only if its current value is
+    /// `==` to `expected`.
+    ///
+    /// Returns `Ok` with the removed value, or otherwise `Err` with the current value, which is
+    /// None if the map did not have this key present.
+    fn try_remove_value<'s: 'guard, Q>(&'s self, key: &Q, expected: &V, guard: &'guard Guard)
+        -> Result<&'guard V, Option<&'guard V>>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let matcher = Match::Value(expected);
+        let value_slot: Option<&ValueSlot<V>> = self.load_inner(guard).put_if_match(
+            KeyCompare::OnlyCompare(key),
+            PutValue::new_tombstone(),
+            matcher,
+            &self.inner,
+            guard
+        );
+        match matcher.matches(value_slot) {
+            true => Ok(ValueSlot::as_inner(value_slot).expect("matched an existing value")),
+            false => Err(ValueSlot::as_inner(value_slot)),
+        }
+    }
+
+}
+
+impl<'guard, 'v: 'guard, K: Hash + Eq + 'guard, V: PartialEq> LockFreeHashMap<'v,K,V> {
+
+    /// Creates a new `LockFreeHashMap`.
+    ///
+    /// # Examples
+    /// ```
+    /// # #![allow(unused_variables)]
+    /// # use lockfreehashmap::LockFreeHashMap;
+    /// let map = LockFreeHashMap::<u32, String>::new();
+    /// ```
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    /// Creates a new `LockFreeHashMap` of a given size. Uses the next power of two if size is not
+    /// a power of two.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::LockFreeHashMap;
+    /// let map = LockFreeHashMap::<u32, String>::with_capacity(12);
+    /// assert_eq!(map.capacity(), 12usize.next_power_of_two());
+    /// assert_eq!(map.capacity(), 16);
+    /// ```
+    pub fn with_capacity(size: usize) -> Self {
+        LockFreeHashMap { inner: AtomicBox::new(MapInner::with_capacity(size)) }
+    }
+}
+
+
+impl<'v, K, V, S> Drop for LockFreeHashMap<'v, K, V, S> {
+    fn drop(&mut self) {
         // self.inner will be dropped because Drop is implemented on `AtomicBox`
         // But if self.inner has pointers to newer maps, then those need to be explicitely dropped.
-        unsafe {
-            self.inner.load(&guard).deref().drop_newer_maps(&guard);
+        MapInner::drop_newer_maps_in(&mut self.inner);
+    }
+}
+
+impl<'v, K, V, S> IntoIterator for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<'v, K, V, S>;
+
+    /// Creates a consuming iterator, which moves each key/value pair out of the map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<i32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(1, "one".to_string(), &guard);
+    /// map.insert(2, "two".to_string(), &guard);
+    ///
+    /// let mut pairs = map.into_iter().collect::<Vec<_>>();
+    /// pairs.sort();
+    /// assert_eq!(pairs, vec![(1, "one".to_string()), (2, "two".to_string())]);
+    /// ```
+    fn into_iter(self) -> IntoIter<'v, K, V, S> {
+        // Finish copying into any newer maps, so that the newest map owns every key/value pair.
+        MapInner::load_newest(&self.inner, &pin());
+        IntoIter {
+            position: 0,
+            map: self,
+        }
+    }
+}
+
+impl<'v, K, V, S> Default for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone + Default,
+{
+    /// Creates an empty `LockFreeHashMap` with the default capacity, using the default value of
+    /// the hasher.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::LockFreeHashMap;
+    /// let map: LockFreeHashMap<u32, String> = Default::default();
+    /// assert_eq!(map.capacity(), LockFreeHashMap::<u32, String>::DEFAULT_CAPACITY);
+    /// assert_eq!(map.len(), 0);
+    /// ```
+    fn default() -> Self {
+        Self::with_capacity_and_hasher(Self::DEFAULT_CAPACITY, S::default())
+    }
+}
+
+impl<'v, K, V, S> Clone for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq + Clone,
+          V: PartialEq + Clone,
+          S: BuildHasher + Clone,
+{
+    /// Creates a new map containing a copy of every key/value pair in `self`.
+    ///
+    /// The new map can hold `len()` key/value pairs while at most half full, so cloning doesn't
+    /// resize it, and it has the same hasher and settings as `self`. If other threads modify
+    /// `self` at the same time, then the copy has the same guarantees as [LockFreeHashMap::iter];
+    /// i.e. it isn't a consistent snapshot of the whole map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<u32, String>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(1, "one".to_string(), &guard);
+    ///
+    /// let copy = map.clone();
+    /// map.insert(2, "two".to_string(), &guard);
+    /// assert_eq!(copy.len(), 1);
+    /// assert_eq!(copy.get(&1, &guard), Some(&"one".to_string()));
+    /// assert_eq!(copy.get(&2, &guard), None);
+    /// ```
+    fn clone(&self) -> Self {
+        let guard = pin();
+        let capacity = MapInner::<K, V, S>::min_capacity_for(self.len());
+        let copy = LockFreeHashMap {
+            inner: AtomicBox::new(self.load_inner(&guard).with_capacity_from(capacity)),
+        };
+        for (key, value) in self.iter(&guard) {
+            copy.insert(key.clone(), value.clone(), &guard);
         }
+        copy
     }
 }
 
-impl<'guard, 'v: 'guard, K: Hash + Eq + fmt::Debug, V: fmt::Debug + PartialEq>
-    fmt::Debug for LockFreeHashMap<'v,K,V>
+impl<'v, K, V, S> Extend<(K, V)> for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
 {
+    /// Inserts every key/value pair of `iter`, replacing the value of any existing key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let mut map = LockFreeHashMap::<u32, u32>::new();
+    /// map.extend((0..100).map(|i| (i, i * 2)));
+    /// assert_eq!(map.len(), 100);
+    /// assert_eq!(map.get(&21, &lockfreehashmap::pin()), Some(&42));
+    /// ```
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let guard = pin();
+        for (key, value) in iter {
+            self.insert(key, value, &guard);
+        }
+    }
+}
+
+impl<'v, K, V, S> FromIterator<(K, V)> for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone + Default,
+{
+    /// Creates a map from the key/value pairs of `iter`. Later values replace earlier values with
+    /// the same key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map: LockFreeHashMap<u32, char> = vec![(1, 'a'), (2, 'b'), (1, 'c')]
+    ///     .into_iter()
+    ///     .collect();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.len(), 2);
+    /// assert_eq!(map.get(&1, &guard), Some(&'c'));
+    /// assert_eq!(map.get(&2, &guard), Some(&'b'));
+    /// ```
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::default();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<'v, K, V, S> fmt::Debug for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq + fmt::Debug,
+          V: PartialEq + fmt::Debug,
+          S: BuildHasher + Clone,
+{
+    /// Formats the key/value pairs of the map like a [HashMap](::std::collections::HashMap).
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMap::<u32, &str>::new();
+    /// map.insert(1, "one", &lockfreehashmap::pin());
+    /// assert_eq!(format!("{:?}", map), r#"{1: "one"}"#);
+    /// ```
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let guard = pin();
-        write!(f, "LockFreeHashMap {{ {:?} }}", self.load_inner(&guard))
+        f.debug_map().entries(self.iter(&guard)).finish()
+    }
+}
+
+impl<'v, K, V, S> PartialEq for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+{
+    /// Returns true if both maps contain the same key/value pairs.
+    ///
+    /// Like [LockFreeHashMap::iter], this doesn't take a consistent snapshot of either map, so the
+    /// result is only meaningful if neither map is modified during the comparison.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let a: LockFreeHashMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+    /// let b: LockFreeHashMap<u32, u32> = (0..10).rev().map(|i| (i, i)).collect();
+    /// assert_eq!(a, b);
+    /// b.insert(3, 4, &lockfreehashmap::pin());
+    /// assert!(a != b);
+    /// ```
+    fn eq(&self, other: &Self) -> bool {
+        let guard = pin();
+        self.len() == other.len() &&
+            self.iter(&guard).all(|(key, value)| other.get(key, &guard) == Some(value))
     }
 }
 
+impl<'v, K, V, S> Eq for LockFreeHashMap<'v, K, V, S>
+    where K: Hash + Eq,
+          V: Eq,
+          S: BuildHasher + Clone,
+{
+}
+
 
 #[derive(Debug)]
 pub struct Keys<'guard, 'v, K, V, S> {
-    position: usize,
-    guard: &'guard Guard, 
-    map: &'guard MapInner<'v, K, V, S>,
+    pairs: PairIter<'guard, 'v, K, V, S, BoxedKey<K>>,
 }
 
-impl<'guard, 'v, K, V, S> Iterator for Keys<'guard, 'v, K, V, S> {
+impl<'guard, 'v, K, V, S> Iterator for Keys<'guard, 'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+{
     type Item = &'guard K;
     fn next(&mut self) -> Option<&'guard K> {
-        while self.position < self.map.capacity() {
-            let (k, v) = self.map.get_at(self.position)
-                .expect("called Vec::get() at a position less than capacity");
-            if let (Some(not_null_k), Some(not_null_v)) =
-                (k.load(self.guard).as_option(), v.load(self.guard).as_option())
-            {
-                if let &KeySlot::Key(ref k) = not_null_k.deref() {
-                    if not_null_v.is_value() || not_null_v.is_valueprime() {
-                        self.position += 1;
-                        return Some(k);
-                    }
-                }
-            }
+        self.pairs.next().map(|(k, _)| k)
+    }
+}
+
+#[derive(Debug)]
+pub struct Values<'guard, 'v, K, V, S> {
+    pairs: PairIter<'guard, 'v, K, V, S, BoxedKey<K>>,
+}
+
+impl<'guard, 'v, K, V, S> Iterator for Values<'guard, 'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+{
+    type Item = &'guard V;
+    fn next(&mut self) -> Option<&'guard V> {
+        self.pairs.next().map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug)]
+pub struct Iter<'guard, 'v, K, V, S> {
+    pairs: PairIter<'guard, 'v, K, V, S, BoxedKey<K>>,
+}
+
+impl<'guard, 'v, K, V, S> Iterator for Iter<'guard, 'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+{
+    type Item = (&'guard K, &'guard V);
+    fn next(&mut self) -> Option<(&'guard K, &'guard V)> {
+        self.pairs.next()
+    }
+}
+
+
+/// An owning iterator over the key/value pairs of a [LockFreeHashMap]. This is created by
+/// `LockFreeHashMap::into_iter()` and `LockFreeHashMap::drain()`.
+pub struct IntoIter<'v, K, V: 'v, S> {
+    position: usize,
+    map: LockFreeHashMap<'v, K, V, S>,
+}
+
+impl<'v, K, V, S> Iterator for IntoIter<'v, K, V, S>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+{
+    type Item = (K, V);
+    fn next(&mut self) -> Option<(K, V)> {
+        let guard = pin();
+        let inner = self.map.load_inner(&guard);
+        while self.position < inner.capacity() {
+            // This is safe because `self` owns the map and `into_iter()` already finished copying
+            // into any newer maps.
+            let pair = unsafe { inner.take_at(self.position, &guard) };
             self.position += 1;
+            if pair.is_some() {
+                return pair;
+            }
         }
-        return None;
+        None
     }
 }
 
-
 #[cfg(test)]
 mod test {
     extern crate rand;
     use super::*;
     use self::rand::Rng;
+    use std::ops::Range;
+
+    /// Runs `f` on `threads` threads at the same time, each pinned with its own guard. Thread `t`
+    /// gets the `per_thread` numbers from `t * per_thread`, e.g. to use as keys, so no two threads
+    /// get the same number.
+    pub(crate) fn run_threads<F>(threads: u32, per_thread: u32, f: F)
+        where F: Fn(Range<u32>, &Guard) + Sync
+    {
+        let f = &f;
+        scope(|scope| {
+            for thread in 0..threads {
+                scope.spawn(move || {
+                    f((thread * per_thread)..((thread + 1) * per_thread), &pin());
+                });
+            }
+        });
+    }
 
     #[test]
     fn test_basic() {
@@ -497,7 +1447,666 @@ mod test {
     }
 
 
+    #[test]
+    fn test_iter_during_resize() {
+        let map = &LockFreeHashMap::<u32, u32>::with_capacity(4);
+        scope(|scope| {
+            for i in 0..64 {
+                scope.spawn(move || {
+                    let guard = pin();
+                    map.insert(i, i * 2, &guard);
+                    for (k, v) in map.iter(&guard) {
+                        assert_eq!(*k * 2, *v);
+                    }
+                });
+            }
+        });
+        let guard = pin();
+        let mut pairs = map.iter(&guard).map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        pairs.sort();
+        assert_eq!((0..64).map(|i| (i, i * 2)).collect::<Vec<_>>(), pairs);
+        let mut values = map.values(&guard).cloned().collect::<Vec<_>>();
+        values.sort();
+        assert_eq!((0..64).map(|i| i * 2).collect::<Vec<_>>(), values);
+    }
+
     use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_entry_or_insert_with_race() {
+        let map = &LockFreeHashMap::<u32, usize>::with_capacity(2);
+        let winners = &Mutex::new(Vec::new());
+        scope(|scope| {
+            for thread in 0..16 {
+                scope.spawn(move || {
+                    let guard = pin();
+                    for key in 0..32 {
+                        let value = *map.entry(key, &guard).or_insert_with(|| thread);
+                        winners.lock().unwrap().push((key, value));
+                    }
+                });
+            }
+        });
+        let guard = pin();
+        assert_eq!(map.len(), 32);
+        for (key, value) in winners.lock().unwrap().iter() {
+            assert_eq!(map.get(key, &guard), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_entry_and_modify_counter() {
+        const NUMBER_OF_THREADS: usize = 16;
+        const INCREMENTS_PER_THREAD: usize = 500;
+        let map = &LockFreeHashMap::<u32, usize>::with_capacity(1);
+        let guard = pin();
+        map.insert(0, 0, &guard);
+        scope(|scope| {
+            for _ in 0..NUMBER_OF_THREADS {
+                scope.spawn(move || {
+                    let guard = pin();
+                    for i in 0..INCREMENTS_PER_THREAD {
+                        // Force a few resizes while the counter is being updated.
+                        map.insert((i % 64) as u32 + 1, i, &guard);
+                        map.entry(0, &guard).and_modify(|v| v + 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(map.get(&0, &guard), Some(&(NUMBER_OF_THREADS * INCREMENTS_PER_THREAD)));
+    }
+
+    #[test]
+    fn test_compare_and_swap_counter() {
+        const NUMBER_OF_THREADS: usize = 16;
+        const INCREMENTS_PER_THREAD: usize = 500;
+        let map = &LockFreeHashMap::<u32, usize>::with_capacity(1);
+        let guard = pin();
+        map.insert(0, 0, &guard);
+        scope(|scope| {
+            for _ in 0..NUMBER_OF_THREADS {
+                scope.spawn(move || {
+                    let guard = pin();
+                    for i in 0..INCREMENTS_PER_THREAD {
+                        // Force a few resizes while the counter is being updated.
+                        map.insert((i % 64) as u32 + 1, i, &guard);
+                        let mut current = *map.get(&0, &guard).unwrap();
+                        while let Err(Some(&actual)) =
+                            map.compare_and_swap(&0, &current, current + 1, &guard)
+                        {
+                            current = actual;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(map.get(&0, &guard), Some(&(NUMBER_OF_THREADS * INCREMENTS_PER_THREAD)));
+    }
+
+    #[test]
+    fn test_compute_counters() {
+        const NUMBER_OF_THREADS: usize = 16;
+        const NUMBER_OF_KEYS: u32 = 64;
+        let map = &LockFreeHashMap::<u32, usize>::with_capacity(1);
+        scope(|scope| {
+            for _ in 0..NUMBER_OF_THREADS {
+                scope.spawn(move || {
+                    let guard = pin();
+                    for key in 0..NUMBER_OF_KEYS {
+                        map.compute(key, |v| Some(v.map_or(1, |v| v + 1)), &guard);
+                        map.update(&key, |v| v + 1, &guard);
+                        // Temporarily add then remove a second count, which must not be lost.
+                        map.compute_if_present(&key, |v| Some(v + 1), &guard);
+                        map.compute_if_present(&key, |v| Some(v - 1), &guard);
+                    }
+                });
+            }
+        });
+        let guard = pin();
+        for key in 0..NUMBER_OF_KEYS {
+            assert_eq!(map.get(&key, &guard), Some(&(2 * NUMBER_OF_THREADS)));
+        }
+    }
+
+    #[test]
+    fn test_conditional_remove() {
+        let map = &LockFreeHashMap::<u32, usize>::with_capacity(1);
+        scope(|scope| {
+            for thread in 0..16 {
+                scope.spawn(move || {
+                    let guard = pin();
+                    for key in 0..64 {
+                        map.insert(key, thread, &guard);
+                        if let Some(removed) = map.remove_if_eq(&key, &thread, &guard) {
+                            assert_eq!(*removed, thread);
+                        }
+                        map.insert(key, thread, &guard);
+                        if let Some(removed) = map.remove_if(&key, |v| v % 2 == 0, &guard) {
+                            assert_eq!(removed % 2, 0);
+                        }
+                    }
+                });
+            }
+        });
+        let guard = pin();
+        assert!(map.values(&guard).all(|v| v % 2 == 1));
+        for key in 0..64 {
+            if let Some((k, v)) = map.remove_entry(&key, &guard) {
+                assert_eq!((*k, v % 2), (key, 1));
+            }
+        }
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn test_try_insert_race() {
+        let map = &LockFreeHashMap::<String, usize>::with_capacity(1);
+        let inserted = &Mutex::new(Vec::new());
+        scope(|scope| {
+            for thread in 0..16 {
+                scope.spawn(move || {
+                    let guard = pin();
+                    for key in 0..64 {
+                        match map.try_insert(key.to_string(), thread, &guard) {
+                            Ok(&value) => inserted.lock().unwrap().push((key, value)),
+                            Err((k, v, _)) => assert_eq!((k, v), (key.to_string(), thread)),
+                        }
+                    }
+                });
+            }
+        });
+        let guard = pin();
+        let mut inserted = inserted.lock().unwrap();
+        inserted.sort();
+        let keys = inserted.iter().map(|&(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys, (0..64).collect::<Vec<_>>());
+        for &(key, value) in inserted.iter() {
+            assert_eq!(map.get(&key.to_string(), &guard), Some(&value));
+        }
+    }
+
+    #[test]
+    fn test_retain_during_inserts() {
+        let map = &LockFreeHashMap::<u32, u32>::with_capacity(1);
+        let guard = pin();
+        for i in 0..256 {
+            map.insert(i, i, &guard);
+        }
+        scope(|scope| {
+            scope.spawn(move || {
+                let guard = pin();
+                for i in 256..512 {
+                    map.insert(i, i, &guard);
+                }
+            });
+            scope.spawn(move || {
+                map.retain(|_, v| v % 2 == 0);
+            });
+        });
+        // Pairs that existed before `retain()` started can only be missed by a concurrent resize.
+        map.retain(|_, v| v % 2 == 0);
+        let mut keys = map.keys(&guard).cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, (0..512).filter(|i| i % 2 == 0).collect::<Vec<_>>());
+        assert_eq!(map.retain(|_, _| false), 256);
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn test_retain_calls_predicate_once() {
+        let map = (0..100).map(|i| (i, i)).collect::<LockFreeHashMap<u32, u32>>();
+        let mut calls = 0;
+        assert_eq!(map.retain(|_, &v| { calls += 1; v % 2 == 0 }), 50);
+        assert_eq!(calls, 100);
+        assert_eq!(map.len(), 50);
+    }
+
+    #[test]
+    fn test_drain_after_resizes() {
+        let mut map = LockFreeHashMap::<u32, u32>::with_capacity(1);
+        run_threads(4, 64, |keys, guard| {
+            for i in keys {
+                map.insert(i, i, guard);
+            }
+        });
+        let capacity = map.capacity();
+        let mut drained = map.drain().collect::<Vec<_>>();
+        drained.sort();
+        assert_eq!(drained, (0..256).map(|i| (i, i)).collect::<Vec<_>>());
+        // The map is empty but keeps its capacity, and can still be used.
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.capacity(), capacity);
+        map.insert(1, 2, &pin());
+        assert_eq!(map.into_iter().collect::<Vec<_>>(), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_iter_during_resizes() {
+        let map = &(0..256).map(|i| (i, i)).collect::<LockFreeHashMap<u32, u32>>();
+        scope(|scope| {
+            scope.spawn(move || {
+                let guard = pin();
+                for i in 256..4096 {
+                    map.insert(i, i, &guard);
+                }
+            });
+            scope.spawn(move || {
+                for _ in 0..20 {
+                    let guard = pin();
+                    // Keys that are in the map the whole time are always returned, even if they're
+                    // copied into a newer map while iterating.
+                    let keys = map.keys(&guard).filter(|&&k| k < 256).count();
+                    assert_eq!(keys, 256);
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn test_clone_during_inserts() {
+        let map = &(0..256).map(|i| (i, i)).collect::<LockFreeHashMap<u32, u32>>();
+        scope(|scope| {
+            scope.spawn(move || {
+                let guard = pin();
+                for i in 256..512 {
+                    map.insert(i, i, &guard);
+                }
+            });
+            scope.spawn(move || {
+                let mut copy = map.clone();
+                let guard = pin();
+                // Keys that existed before cloning are always copied.
+                assert!((0..256).all(|i| copy.get(&i, &guard) == Some(&i)));
+                copy.extend((0..512).map(|i| (i, i + 1)));
+                assert!((0..512).all(|i| copy.get(&i, &guard) == Some(&(i + 1))));
+            });
+        });
+        assert_eq!(map.len(), 512);
+    }
+
+    #[test]
+    fn test_clone_capacity() {
+        for &len in &[0, 1, 10, 1000] {
+            let map = (0..len).map(|i| (i, i)).collect::<LockFreeHashMap<u32, u32>>();
+            let copy = map.clone();
+            assert_eq!(copy.len(), len as usize);
+            assert!(copy.capacity() >= 2 * copy.len());
+            assert!(copy.capacity() <= map.capacity());
+        }
+    }
+
+    #[test]
+    fn test_debug_and_eq_with_custom_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+        type Map = LockFreeHashMap<'static, u32, &'static str, BuildHasherDefault<DefaultHasher>>;
+        let a: Map = vec![(1, "one")].into_iter().collect();
+        let b = Map::with_capacity_and_hasher(1, Default::default());
+        assert_eq!(format!("{:?}", b), "{}");
+        assert!(a != b);
+        b.insert(1, "one", &pin());
+        assert_eq!(a, b);
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        assert_eq!(format!("{:?}", a), r#"{1: "one"}"#);
+    }
+
+    #[test]
+    fn test_reprobe_limit_with_colliding_hashes() {
+        use std::hash::{BuildHasherDefault, Hasher};
+        #[derive(Default)]
+        struct ConstantHasher;
+        impl Hasher for ConstantHasher {
+            fn finish(&self) -> u64 { 0 }
+            fn write(&mut self, _bytes: &[u8]) {}
+        }
+        let map = LockFreeHashMap::<u32, u32, BuildHasherDefault<ConstantHasher>>
+            ::with_capacity_and_hasher(64, Default::default());
+        let guard = pin();
+        // Every key collides, so only `10 + 64/4` keys fit before the map must resize.
+        for i in 0..26 {
+            map.insert(i, i, &guard);
+        }
+        assert_eq!(map.capacity(), 64);
+        assert_eq!(map.get(&26, &guard), None);
+        map.insert(26, 26, &guard);
+        assert!(map.capacity() > 64);
+        assert!((0..27).all(|i| map.get(&i, &guard) == Some(&i)));
+        assert_eq!(map.remove(&27, &guard), None);
+        assert_eq!(map.len(), 27);
+    }
+
+    #[test]
+    fn test_keys_are_hashed_and_compared_once() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        #[derive(Clone)]
+        struct CountingBuildHasher(Arc<AtomicUsize>);
+        impl ::std::hash::BuildHasher for CountingBuildHasher {
+            type Hasher = DefaultHasher;
+            fn build_hasher(&self) -> DefaultHasher {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                DefaultHasher::new()
+            }
+        }
+        struct Key(u32, Arc<AtomicUsize>);
+        impl ::std::hash::Hash for Key {
+            fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+                self.0.hash(state);
+            }
+        }
+        impl Eq for Key {}
+        impl PartialEq for Key {
+            fn eq(&self, other: &Key) -> bool {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0 == other.0
+            }
+        }
+        let hashes = Arc::new(AtomicUsize::new(0));
+        let comparisons = Arc::new(AtomicUsize::new(0));
+        let map = LockFreeHashMap::<Key, u32, _>
+            ::with_capacity_and_hasher(1, CountingBuildHasher(hashes.clone()));
+        let guard = pin();
+        for i in 0..1000 {
+            map.insert(Key(i, comparisons.clone()), i, &guard);
+        }
+        // The map was resized several times, but the keys weren't hashed again to copy them.
+        assert!(map.capacity() >= 1024);
+        assert_eq!(hashes.load(Ordering::SeqCst), 1000);
+        assert_eq!(comparisons.load(Ordering::SeqCst), 0);
+        for i in 0..1000 {
+            assert_eq!(map.get(&Key(i, comparisons.clone()), &guard), Some(&i));
+        }
+        // Only the key with the same hash is compared.
+        assert_eq!(hashes.load(Ordering::SeqCst), 2000);
+        assert_eq!(comparisons.load(Ordering::SeqCst), 1000);
+    }
+
+    #[test]
+    fn test_removed_keys_dont_grow_map() {
+        let map = LockFreeHashMap::<u32, u32>::with_capacity(64);
+        let guard = pin();
+        for i in 0..10_000 {
+            map.insert(i, i, &guard);
+            assert_eq!(map.remove(&i, &guard), Some(&i));
+        }
+        // Resizing a map full of removed keys just copies it into a map of the same size.
+        assert_eq!(map.capacity(), 64);
+        assert_eq!(map.len(), 0);
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        assert!(map.capacity() > 64);
+        assert!((0..100).all(|i| map.get(&i, &guard) == Some(&i)));
+    }
+
+    #[test]
+    fn test_len_during_inserts_and_removes() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        // Big enough that the map is never resized, so no key/value pair is counted twice.
+        let map = &LockFreeHashMap::<u32, u32>::with_capacity(4096);
+        let done = &AtomicBool::new(false);
+        scope(|scope| {
+            scope.spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    assert!(map.len() <= 128);
+                    assert!(map.len_estimate() <= 128);
+                }
+            });
+            // Each odd thread removes the keys of the even thread before it, so a key is counted
+            // on one thread's stripe and uncounted on another's.
+            run_threads(4, 64, |keys, guard| {
+                let inserting = keys.start % 128 == 0;
+                let keys = if inserting { keys } else { (keys.start - 64)..(keys.end - 64) };
+                for _ in 0..500 {
+                    for i in keys.clone() {
+                        if inserting {
+                            map.insert(i, i, guard);
+                        } else {
+                            map.remove(&i, guard);
+                        }
+                    }
+                }
+            });
+            done.store(true, Ordering::SeqCst);
+        });
+        assert!(map.len() <= 128);
+        assert_eq!(map.capacity(), 4096);
+    }
+
+    #[test]
+    fn test_shrink_during_inserts() {
+        let map = &LockFreeHashMap::<u32, u32>::with_capacity(4096);
+        scope(|scope| {
+            scope.spawn(move || {
+                for _ in 0..8 {
+                    map.shrink_to_fit();
+                }
+            });
+            run_threads(4, 256, |keys, guard| {
+                for i in keys {
+                    map.insert(i, i, guard);
+                }
+            });
+        });
+        let guard = pin();
+        assert_eq!(map.len(), 1024);
+        assert!((0..1024).all(|i| map.get(&i, &guard) == Some(&i)));
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), 2048);
+        assert!((0..1024).all(|i| map.remove(&i, &guard) == Some(&i)));
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), 1);
+    }
+
+    #[test]
+    fn test_reserve_during_inserts() {
+        let map = &LockFreeHashMap::<u32, u32>::with_capacity(1);
+        scope(|scope| {
+            scope.spawn(move || map.reserve(4096));
+            run_threads(4, 256, |keys, guard| {
+                for i in keys {
+                    map.insert(i, i, guard);
+                }
+            });
+        });
+        let guard = pin();
+        assert!(map.capacity() >= 8192);
+        assert_eq!(map.len(), 1024);
+        assert!((0..1024).all(|i| map.get(&i, &guard) == Some(&i)));
+        // Already big enough, so nothing changes.
+        let capacity = map.capacity();
+        map.reserve(1024);
+        assert_eq!(map.capacity(), capacity);
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity overflow")]
+    fn test_reserve_overflow() {
+        let map = LockFreeHashMap::<u32, u32>::new();
+        map.insert(1, 1, &pin());
+        map.reserve(usize::MAX);
+    }
+
+    #[test]
+    fn test_resize_contention_allocates_one_newer_map() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+        // Every newer map clones the hasher exactly once, so this counts the allocated maps.
+        struct CountingHasher(Arc<AtomicUsize>);
+        impl Clone for CountingHasher {
+            fn clone(&self) -> Self {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                CountingHasher(self.0.clone())
+            }
+        }
+        impl ::std::hash::BuildHasher for CountingHasher {
+            type Hasher = DefaultHasher;
+            fn build_hasher(&self) -> DefaultHasher {
+                DefaultHasher::new()
+            }
+        }
+        // Always grow into a 2 MB map.
+        struct Huge;
+        impl ResizePolicy for Huge {
+            fn new_capacity(&self, _stats: &ResizeStats) -> usize {
+                1 << 17
+            }
+        }
+        let strategies = [
+            ResizeContention::Spin(u32::MAX),
+            ResizeContention::Yield(u32::MAX),
+            ResizeContention::Sleep(Duration::from_millis(50)),
+        ];
+        for &strategy in strategies.iter() {
+            let allocated = Arc::new(AtomicUsize::new(0));
+            let map = &LockFreeHashMapBuilder::new()
+                .capacity(1)
+                .hasher(CountingHasher(allocated.clone()))
+                .resize_policy(Huge)
+                .resize_contention(strategy)
+                .build::<u32, u32>();
+            map.insert(0, 0, &pin());
+            // The map is full, so every thread races to resize it.
+            scope(|scope| {
+                for thread in 1..17 {
+                    scope.spawn(move || {
+                        map.insert(thread, thread, &pin());
+                    });
+                }
+            });
+            assert_eq!(allocated.load(Ordering::SeqCst), 1, "{:?}", strategy);
+            assert_eq!(map.capacity(), 1 << 17);
+            assert!((0..17).all(|i| map.get(&i, &pin()) == Some(&i)));
+        }
+    }
+
+    #[test]
+    fn test_auto_shrink() {
+        let map = &LockFreeHashMapBuilder::new().auto_shrink(0.25).build::<u32, u32>();
+        run_threads(4, 1000, |keys, guard| {
+            for i in keys.clone() {
+                map.insert(i, i, guard);
+            }
+            // Keep the last two keys of each thread.
+            for i in keys.start..(keys.end - 2) {
+                assert_eq!(map.remove(&i, guard), Some(&i));
+            }
+        });
+        let guard = pin();
+        assert_eq!(map.len(), 8);
+        assert!(map.capacity() <= 64);
+        assert!((0..4).all(|t| map.get(&(t * 1000 + 999), &guard) == Some(&(t * 1000 + 999))));
+    }
+
+    #[test]
+    fn test_auto_shrink_copies_incrementally() {
+        let map = LockFreeHashMapBuilder::new()
+            .capacity(4096)
+            .auto_shrink(0.25)
+            .build::<u32, u32>();
+        let guard = pin();
+        for i in 0..1024 {
+            map.insert(i, i, &guard);
+        }
+        let mut removed = 0;
+        while !map.load_inner(&guard).newer_map.relaxed_exists(&guard) {
+            map.remove(&removed, &guard);
+            removed += 1;
+        }
+        // The removal that shrank the map only copied one chunk into the smaller map.
+        assert_eq!(map.capacity(), 4096);
+        // Every removal after it copies another chunk.
+        while map.capacity() == 4096 {
+            assert!(removed < (4096 / COPY_CHUNK_SIZE) as u32);
+            map.remove(&removed, &guard);
+            removed += 1;
+        }
+        assert_eq!(map.capacity(), 2048);
+        assert_eq!(map.len(), 1024 - removed as usize);
+        assert!((removed..1024).all(|i| map.get(&i, &guard) == Some(&i)));
+    }
+
+    #[test]
+    fn test_copy_budget() {
+        /// Inserts keys until one of them makes the map resize, and returns the number of keys.
+        fn fill_until_resize(map: &LockFreeHashMap<u32, u32>, guard: &Guard) -> u32 {
+            let capacity = map.capacity();
+            let mut len = 0;
+            while map.capacity() == capacity
+                && !map.load_inner(guard).newer_map.relaxed_exists(guard)
+            {
+                map.insert(len, len, guard);
+                len += 1;
+            }
+            len
+        }
+        let guard = &pin();
+        // By default, the insert that starts the resize copies the entire map.
+        let map = LockFreeHashMapBuilder::new().capacity(256).build();
+        let len = fill_until_resize(&map, guard);
+        assert!(map.capacity() > 256);
+        assert!((0..len).all(|i| map.get(&i, guard) == Some(&i)));
+
+        // With a budget, every insert copies its own key slot and at most one chunk, including
+        // the insert that starts the resize.
+        let map = LockFreeHashMapBuilder::new()
+            .capacity(256)
+            .copy_chunk_size(8)
+            .copy_budget(1)
+            .build();
+        let len = fill_until_resize(&map, guard);
+        assert_eq!(map.capacity(), 256);
+        // Counting the keys doesn't finish the resize.
+        assert_eq!(map.len(), len as usize);
+        assert_eq!(map.len_estimate(), len as usize);
+        assert_eq!(map.capacity(), 256);
+        let mut inserts = 0;
+        while map.capacity() == 256 {
+            map.insert(inserts % len, inserts % len, guard);
+            inserts += 1;
+        }
+        assert!(inserts >= 256 / 8 - 1, "copied in {} inserts", inserts);
+        assert!((0..len).all(|i| map.get(&i, guard) == Some(&i)));
+
+        // Resizes that are copied a little at a time can still overlap.
+        let map = &LockFreeHashMapBuilder::new()
+            .capacity(1)
+            .copy_chunk_size(1)
+            .copy_budget(1)
+            .build::<u32, u32>();
+        run_threads(4, 1000, |keys, guard| {
+            for i in keys {
+                map.insert(i, i, guard);
+                assert_eq!(map.get(&i, guard), Some(&i));
+            }
+        });
+        assert_eq!(map.len(), 4000);
+        assert!((0..4000).all(|i| map.get(&i, guard) == Some(&i)));
+    }
+
+    #[test]
+    fn test_aligned_table_layout() {
+        let map = &LockFreeHashMapBuilder::new()
+            .capacity(1)
+            .table_layout(TableLayout::Aligned)
+            .build::<u32, String>();
+        run_threads(4, 500, |keys, guard| {
+            for i in keys.clone() {
+                map.insert(i, i.to_string(), guard);
+            }
+            for i in keys.filter(|i| i % 2 == 0) {
+                assert_eq!(map.remove(&i, guard), Some(&i.to_string()));
+            }
+        });
+        let guard = pin();
+        assert_eq!(map.len(), 1000);
+        for i in 0..2000 {
+            let expected = if i % 2 == 0 { None } else { Some(i.to_string()) };
+            assert_eq!(map.get(&i, &guard), expected.as_ref());
+        }
+    }
+
     #[derive(Clone)]
     pub struct NumberWithDrop {
         number: u64,
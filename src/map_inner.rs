@@ -134,16 +134,28 @@
 //! Therefore, instead of looping and waiting until all transitions have finished,
 //!     which is essentially a blocking algorithm,
 //!     each thread can help make progress by doing any (or all) of the 4 transitions above.
+//!
+//! A `MapInner` is generic over how its key slots store these states, see the
+//! [key_slot](::key_slot) module. Every map except [LockFreeHashMapU64](::LockFreeHashMapU64)
+//! uses the default, a pointer to a `KeySlot`.
 
 use crossbeam_epoch::{Guard, Shared};
-use std::borrow::Borrow;
+use std::cell::Cell;
 use std::collections::hash_map::RandomState;
 use std::hash::{BuildHasher, Hash, Hasher};
-use std::num::Wrapping;
+use std::marker::PhantomData;
+use std::ops::Index;
+use std::ptr;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
 
-use atomic::{AtomicBox, AtomicPtr, MaybeNull, NotNull, NotNullOwned};
+use atomic::{AtomicBox, AtomicPtr, MaybeNull, NotNull, NotNullOwned, RMW_ORDERING};
+use builder::Config;
+use counter::StripedCounter;
+use equivalent::Equivalent;
+use key_slot::{AtomicKey, BoxedKey, LoadedKey};
+use layout::{CachePadded, TableLayout};
+use resize::ResizeStats;
 
 #[derive(Debug)]
 /// The hash map is implemented as an array of key-value pairs, where each key and value can be one
@@ -163,14 +175,18 @@ pub enum KeySlot<K> {
 /// The hash map is implemented as an array of key-value pairs, where each key and value can be one
 /// of several states. This enum represents the various states that a value can be in, excluding
 /// the null/empty state.
-pub enum ValueSlot<'v, V: 'v> {
+///
+/// Only `Value`s are allocated for each write. The other variants are [Sentinels], which are
+/// allocated once per `LockFreeHashMap`. The state of a value that is being copied into a newer
+/// table (V') is a tagged pointer to its `Value` instead. See `NotNull::is_valueprime()`.
+///
+/// This is aligned to at least 2 bytes, so that pointers to it have room for that tag.
+#[repr(align(2))]
+pub enum ValueSlot<V> {
     /// A value has been inserted into the table.
     Value(V),
     /// This state represents that a key has been inserted but then removed.
     Tombstone,
-    /// The table is being resized currently and the value here still needs to be inserted into the
-    /// newer table.
-    ValuePrime(&'v ValueSlot<'v, V>),
     /// This state represents one of two things:
     ///     1) This was a `ValueSlot::Tombstone(_)` slot that is now taken. There is a newer
     ///        (resized) table that should be used if this value slot was needed.
@@ -178,9 +194,13 @@ pub enum ValueSlot<'v, V: 'v> {
     ///        newer table.
     /// This is the final state for any `ValueSlot`.
     SeeNewTable,
+    /// Like `SeeNewTable`, but this was an empty value slot, i.e. a key without a value yet. This
+    /// is the only final state where a value that is still being copied from an older table
+    /// might not be in the newer table yet. See `Match::Empty`.
+    EmptySeeNewTable,
 }
 
-impl<'v, V> ValueSlot<'v, V> {
+impl<V> ValueSlot<V> {
     /// Returns true if and only if the `ValueSlot` has discriminant `Tombstone`.
     pub fn is_tombstone(&self) -> bool {
         match self {
@@ -189,15 +209,8 @@ impl<'v, V> ValueSlot<'v, V> {
         }
     }
 
-    /// Returns true if and only if the `ValueSlot` has discriminant `ValuePrime`.
-    pub fn is_valueprime(&self) -> bool {
-        match self {
-            &ValueSlot::ValuePrime(_) => true,
-            _ => false,
-        }
-    }
-
-    /// Returns true if and only if the `ValueSlot` has discriminant `Value`.
+    /// Returns true if and only if the `ValueSlot` has discriminant `Value`. Note that this is
+    /// also true for a value that is being copied, which is only known from the pointer to it.
     pub fn is_value(&self) -> bool {
         match self {
             &ValueSlot::Value(_) => true,
@@ -205,19 +218,11 @@ impl<'v, V> ValueSlot<'v, V> {
         }
     }
 
-    /// Returns true if and only if the `ValueSlot` has discriminant `SeeNewTable`.
+    /// Returns true if and only if the `ValueSlot` has discriminant `SeeNewTable` or
+    /// `EmptySeeNewTable`.
     pub fn is_seenewtable(&self) -> bool {
         match self {
-            &ValueSlot::SeeNewTable => true,
-            _ => false,
-        }
-    }
-
-    /// Returns true if and only if the `ValueSlot` has either discriminant `ValuePrime` or
-    /// `SeeNewTable`.
-    pub fn is_prime(&self) -> bool {
-        match self {
-            &ValueSlot::SeeNewTable | &ValueSlot::ValuePrime(_) => true,
+            &ValueSlot::SeeNewTable | &ValueSlot::EmptySeeNewTable => true,
             _ => false,
         }
     }
@@ -226,79 +231,154 @@ impl<'v, V> ValueSlot<'v, V> {
     pub fn as_inner(value: Option<&Self>) -> Option<&V> {
         match value {
             Some(&ValueSlot::Value(ref v)) => Some(&v),
-            Some(&ValueSlot::ValuePrime(v)) => ValueSlot::as_inner(Some(v)),
             _ => None,
         }
     }
 }
 
+impl<'t, V> NotNull<'t, ValueSlot<V>> {
+    /// Returns true if and only if this points to a `Value` that is being copied into the newer
+    /// table, i.e. V'. This is the only way a pointer to a `ValueSlot` is tagged.
+    pub fn is_valueprime(&self) -> bool {
+        self.is_tagged()
+    }
+
+    /// Returns true if and only if this is V', or points to a `SeeNewTable` or `EmptySeeNewTable`.
+    pub fn is_prime(&self) -> bool {
+        self.is_valueprime() || self.deref().is_seenewtable()
+    }
+}
+
+/// The `ValueSlot`s other than `Value` and the `KeySlot::SeeNewTable`, which are compared by
+/// pointer and shared by every slot in that state. Every map that a `LockFreeHashMap` is resized
+/// into uses the same ones, so that removing a key or copying a slot doesn't allocate.
+///
+/// A [LockFreeHashSet](::LockFreeHashSet) also shares a single `Value` between all of its keys,
+/// so that inserting a key doesn't allocate a value either.
+#[derive(Debug)]
+pub struct Sentinels<K, V> {
+    key_see_new_table: Box<KeySlot<K>>,
+    tombstone: Box<ValueSlot<V>>,
+    see_new_table: Box<ValueSlot<V>>,
+    empty_see_new_table: Box<ValueSlot<V>>,
+    value: Option<Box<ValueSlot<V>>>,
+}
+
+impl<K, V> Sentinels<K, V> {
+    pub fn new() -> Self {
+        Sentinels {
+            key_see_new_table: Box::new(KeySlot::SeeNewTable),
+            tombstone: Box::new(ValueSlot::Tombstone),
+            see_new_table: Box::new(ValueSlot::SeeNewTable),
+            empty_see_new_table: Box::new(ValueSlot::EmptySeeNewTable),
+            value: None,
+        }
+    }
+
+    /// Creates the sentinels of a map whose keys all share `value`. See `MapInner::shared_value()`.
+    pub fn with_value(value: V) -> Self {
+        Sentinels {
+            value: Some(Box::new(ValueSlot::Value(value))),
+            ..Sentinels::new()
+        }
+    }
+
+    /// Returns the sentinel for `state`, which must not be a `ValueSlot::Value`.
+    pub fn get(&self, state: ValueSlot<V>) -> &ValueSlot<V> {
+        match state {
+            ValueSlot::Tombstone => &self.tombstone,
+            ValueSlot::SeeNewTable => &self.see_new_table,
+            ValueSlot::EmptySeeNewTable => &self.empty_see_new_table,
+            ValueSlot::Value(_) => unreachable!("a `ValueSlot::Value` isn't a sentinel"),
+        }
+    }
+
+    /// Returns true if and only if `value` is one of these sentinels, i.e. it isn't owned by the
+    /// value slot that it's in.
+    pub fn contains_value(&self, value: &ValueSlot<V>) -> bool {
+        ptr::eq(value, &*self.tombstone)
+            || ptr::eq(value, &*self.see_new_table)
+            || ptr::eq(value, &*self.empty_see_new_table)
+            || self.value.as_ref().is_some_and(|shared| ptr::eq(value, &**shared))
+    }
+}
+
 /// Sometimes, when inserting a new value into the hash map, we only want to insert something if
 /// the value already matches something.
 ///
 /// This enum represents what key/value pair to match when searching in `put_if_match()`.
 #[derive(Debug)]
-pub enum Match {
-    /// Match if there is no key/value pair in the map
+pub enum Match<'m, V: 'm> {
+    /// Match if there is no key/value pair in the map, and there never was one. Unlike the other
+    /// variants, a tombstone doesn't match. This is only used to copy a value into a newer map.
     Empty,
+    /// Match if there is no value in the map, i.e. the value slot is either empty or a tombstone
+    NoValue,
     /// Match if there is a key/value pair in the map
     AnyKeyValuePair,
+    /// Match if there is a key/value pair in the map, whose value is `==` to this value
+    Value(&'m V),
     /// Always match
     Always,
 }
 
-/// Sometimes when calling `put_if_match()` we want to insert a key and sometimes we just want to
-/// compare it with some variable of type `Q`. This enum represents which one is intended.
-pub enum KeyCompare<'k, 'q, K: 'k + Borrow<Q>, Q: 'q + ?Sized> {
-    Owned(NotNullOwned<KeySlot<K>>),
-    Shared(NotNull<'k, KeySlot<K>>),
-    OnlyCompare(&'q Q),
+impl<'m, V> Clone for Match<'m, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
 }
 
-impl<'k, 'q, K: Borrow<Q>, Q: ?Sized> KeyCompare<'k, 'q, K, Q> {
-    pub fn new(key: K) -> Self {
-        KeyCompare::Owned(NotNullOwned::new(KeySlot::Key(key)))
-    }
-    /// The purpose of this function is to ultimately get a value of type `&Q`.
-    /// Because we need to call `deref()` and `borrow()` a few times, we need to put the result of
-    /// these functions somewhere in order to return a reference. Thus, `QRef` and `QRef2` are
-    /// introduced as helper types to place these values somewhere.
-    fn as_qref(&self) -> QRef<K, Q> {
-        match self {
-            &KeyCompare::Owned(ref owned) => QRef::Shared(owned),
-            &KeyCompare::Shared(ref not_null) => QRef::Shared(not_null),
-            &KeyCompare::OnlyCompare(q) => QRef::Borrow(q),
+impl<'m, V> Copy for Match<'m, V> { }
+
+impl<'m, V: PartialEq> Match<'m, V> {
+    /// Returns true if and only if `value`, the previous value returned by `put_if_match()`,
+    /// matches this `Match`. In other words, this is true when `put_if_match()` actually inserted
+    /// its value.
+    pub fn matches(&self, value: Option<&ValueSlot<V>>) -> bool {
+        match (*self, value) {
+            (Match::Empty, None) => true,
+            (Match::Empty, Some(_)) => false,
+            (Match::NoValue, None) | (Match::NoValue, Some(&ValueSlot::Tombstone)) => true,
+            (Match::NoValue, Some(_)) => false,
+            (Match::AnyKeyValuePair, None) | (Match::AnyKeyValuePair, Some(&ValueSlot::Tombstone))
+                => false,
+            (Match::AnyKeyValuePair, Some(_)) => true,
+            (Match::Value(expected), value) => ValueSlot::as_inner(value) == Some(expected),
+            (Match::Always, _) => true,
         }
     }
 }
 
-/// See `KeyCompare::as_qref()` for the motivation behind this type.
-enum QRef<'k, 'q, K: 'k + Borrow<Q>, Q: 'q + ?Sized> {
-    Shared(&'k KeySlot<K>),
-    Borrow(&'q Q),
+/// Sometimes when calling `put_if_match()` we want to insert a key and sometimes we just want to
+/// compare it with some variable of type `Q`. This enum represents which one is intended.
+///
+/// The keys that can be inserted are in the form that the key slots `A` of the map store them.
+pub enum KeyCompare<'k, 'q, K: 'k, Q: 'q + ?Sized, A: AtomicKey<K> = BoxedKey<K>> {
+    Owned(A::Owned),
+    Shared(A::Shared<'k>),
+    /// A key that is owned by the caller, who takes back ownership of it unless it was inserted
+    /// into a key slot. The `Cell` is set to true if and only if it was inserted.
+    Reclaimable(A::Shared<'k>, &'q Cell<bool>),
+    OnlyCompare(&'q Q),
 }
 
-impl<'k, 'q, K: Borrow<Q>, Q: ?Sized> QRef<'k, 'q, K, Q> {
-    fn as_qref2(&self) -> QRef2<K, Q> {
-        match self {
-            &QRef::Shared(&KeySlot::Key(ref k)) => QRef2::Shared(k),
-            &QRef::Shared(&KeySlot::SeeNewTable) =>
-                unreachable!("KeyCompare must contain a `NotNull(KeySlot::Key(K))`"),
-            &QRef::Borrow(q) => QRef2::Borrow(q),
-        }
+impl<'k, 'q, K, A: AtomicKey<K>> KeyCompare<'k, 'q, K, K, A> {
+    /// Creates a key to insert. Since it's never only compared, `Q` is just `K`.
+    pub fn new(key: K) -> Self {
+        KeyCompare::Owned(A::new_key(key))
     }
 }
 
-/// See `KeyCompare::as_qref()` for the motivation behind this type.
-enum QRef2<'k, 'q, K: 'k + Borrow<Q>, Q: 'q + ?Sized> {
-    Shared(&'k K),
-    Borrow(&'q Q),
-}
-
-impl<'k, 'q, K: Borrow<Q>, Q: ?Sized> QRef2<'k, 'q, K, Q> {
-    fn as_q(&self) -> &Q {
+impl<'k, 'q, K, Q: ?Sized, A: AtomicKey<K>> KeyCompare<'k, 'q, K, Q, A> {
+    /// Returns `Ok` with the key if it can be inserted, or `Err` with the value that it's only
+    /// compared with. The two are hashed and compared with the keys in the map in the same way,
+    /// since every `K` is `Equivalent` to itself.
+    fn as_key(&self) -> Result<&K, &'q Q> {
         match self {
-            &QRef2::Shared(k) => k.borrow(),
-            &QRef2::Borrow(q) => q,
+            &KeyCompare::Owned(ref owned) => Ok(A::owned_key(owned)),
+            &KeyCompare::Shared(ref shared) => Ok(A::shared_key(shared)),
+            &KeyCompare::Reclaimable(ref shared, _) => Ok(A::shared_key(shared)),
+            &KeyCompare::OnlyCompare(q) => Err(q),
         }
     }
 }
@@ -307,33 +387,27 @@ impl<'k, 'q, K: Borrow<Q>, Q: ?Sized> QRef2<'k, 'q, K, Q> {
 /// when called from `LockFreeHashMap` and shared if its been copied from a previous, smaller map.
 #[derive(Debug)]
 pub enum PutValue<'v, V: 'v> {
-    Owned(NotNullOwned<ValueSlot<'v, V>>),
-    Shared(NotNull<'v, ValueSlot<'v, V>>),
+    Owned(NotNullOwned<ValueSlot<V>>),
+    Shared(NotNull<'v, ValueSlot<V>>),
+    /// The map's `ValueSlot::Tombstone` sentinel, which `put_if_match()` replaces with a
+    /// `PutValue::Shared` pointer to it.
+    Tombstone,
 }
 
 impl<'v, V: PartialEq> PutValue<'v, V> {
     pub fn new(value: V) -> Self {
         PutValue::Owned(NotNullOwned::new(ValueSlot::Value(value)))
     }
-    /// Returns a new `PutValue` containing an owned `ValueSlot::Tombstone` value.
+    /// Returns a new `PutValue` that removes the key, i.e. a `ValueSlot::Tombstone` value.
     pub fn new_tombstone() -> Self {
-        PutValue::Owned(NotNullOwned::new(ValueSlot::Tombstone))
+        PutValue::Tombstone
     }
     /// Returns true if and only if the inner `ValueSlot` has discriminant `Tombstone`.
     pub fn is_tombstone(&self) -> bool {
         match self {
-            &PutValue::Owned(ref owned) => if let ValueSlot::Tombstone = **owned {
-                true
-            } else {
-                false
-            },
-            &PutValue::Shared(ref not_null) => {
-                if let &ValueSlot::Tombstone = &**not_null {
-                    true
-                } else {
-                    false
-                }
-            },
+            &PutValue::Owned(ref owned) => owned.is_tombstone(),
+            &PutValue::Shared(ref not_null) => not_null.deref().is_tombstone(),
+            &PutValue::Tombstone => true,
         }
     }
 
@@ -345,64 +419,336 @@ impl<'v, V: PartialEq> PutValue<'v, V> {
         }
     }
 
-    pub fn as_raw(&self) -> *const ValueSlot<'v, V> {
+    pub fn as_raw(&self) -> *const ValueSlot<V> {
         match self {
             &PutValue::Owned(ref not_null) => &**not_null as *const _,
             &PutValue::Shared(ref not_null) => &**not_null as *const _,
+            &PutValue::Tombstone => unreachable!("`put_if_match()` replaces `PutValue::Tombstone`"),
         }
     }
 }
 
-pub type KVPair<'v, K, V> = (AtomicPtr<KeySlot<K>>, AtomicPtr<ValueSlot<'v, V>>);
+/// A key slot and a value slot. The key slot is an [AtomicKey], e.g. a [BoxedKey], which also
+/// stores the full hash of its key.
+pub type KVPair<A, V> = (A, AtomicPtr<ValueSlot<V>>);
+
+/// The array of key/value pairs of a `MapInner`, which frees its keys and values when dropped.
+///
+/// This is reference counted because `copy_slot()` defers a function that checks whether a key
+/// slot was tagged, and that function can run after the `MapInner` itself has been dropped.
+#[derive(Debug)]
+struct Slots<K, V, A: AtomicKey<K>> {
+    pairs: Pairs<A, V>,
+    /// The sentinels that the value slots point to, which are shared with the other maps.
+    sentinels: Arc<Sentinels<K, V>>,
+}
+
+/// The key/value pairs of a map, in one of the layouts of [TableLayout].
+#[derive(Debug)]
+enum Pairs<A, V> {
+    Packed(Vec<KVPair<A, V>>),
+    Aligned(Vec<AlignedKVPair<A, V>>),
+}
+
+/// A `KVPair` that is never split over two cache lines. See `TableLayout::Aligned`.
+#[derive(Debug)]
+#[repr(align(32))]
+struct AlignedKVPair<A, V>(KVPair<A, V>);
+
+impl<K, V, A: AtomicKey<K>> Slots<K, V, A> {
+    fn new(size: usize, layout: TableLayout, sentinels: Arc<Sentinels<K, V>>) -> Self {
+        let new_pair = || (A::empty(), AtomicPtr::new(None));
+        let pairs = match layout {
+            TableLayout::Packed => Pairs::Packed((0..size).map(|_| new_pair()).collect()),
+            TableLayout::Aligned => {
+                Pairs::Aligned((0..size).map(|_| AlignedKVPair(new_pair())).collect())
+            },
+        };
+        Slots { pairs, sentinels }
+    }
+
+    /// Returns the size in bytes of a key/value pair in `layout`.
+    fn pair_size(layout: TableLayout) -> usize {
+        match layout {
+            TableLayout::Packed => ::std::mem::size_of::<KVPair<A, V>>(),
+            TableLayout::Aligned => ::std::mem::size_of::<AlignedKVPair<A, V>>(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    fn get(&self, pos: usize) -> Option<&KVPair<A, V>> {
+        match self.pairs {
+            Pairs::Packed(ref pairs) => pairs.get(pos),
+            Pairs::Aligned(ref pairs) => pairs.get(pos).map(|pair| &pair.0),
+        }
+    }
+}
+
+impl<A, V> Pairs<A, V> {
+    fn len(&self) -> usize {
+        match *self {
+            Pairs::Packed(ref pairs) => pairs.len(),
+            Pairs::Aligned(ref pairs) => pairs.len(),
+        }
+    }
+
+    fn get_mut(&mut self, pos: usize) -> &mut KVPair<A, V> {
+        match *self {
+            Pairs::Packed(ref mut pairs) => &mut pairs[pos],
+            Pairs::Aligned(ref mut pairs) => &mut pairs[pos].0,
+        }
+    }
+}
+
+impl<K, V, A: AtomicKey<K>> Index<usize> for Slots<K, V, A> {
+    type Output = KVPair<A, V>;
+    fn index(&self, pos: usize) -> &KVPair<A, V> {
+        match self.pairs {
+            Pairs::Packed(ref pairs) => &pairs[pos],
+            Pairs::Aligned(ref pairs) => &pairs[pos].0,
+        }
+    }
+}
+
+impl<K, V, A: AtomicKey<K>> Drop for Slots<K, V, A> {
+    fn drop(&mut self) {
+        // No other thread can have a reference to any of the slots at this point.
+        let guard = unsafe { ::crossbeam_epoch::unprotected() };
+        let Slots { ref mut pairs, ref sentinels } = *self;
+        for pos in 0..pairs.len() {
+            let &mut (ref mut k_ptr, ref mut v_ptr) = pairs.get_mut(pos);
+            unsafe {
+                k_ptr.drop_key(&sentinels.key_see_new_table);
+                // A V' is freed by the thread that copied it, once it knows if the newer map owns
+                // it. Sentinels aren't owned by any value slot.
+                let owned = v_ptr.load(guard).as_option().is_some_and(|value| {
+                    !value.is_valueprime() && !sentinels.contains_value(value.deref())
+                });
+                if owned {
+                    v_ptr.try_drop(guard);
+                }
+            }
+        }
+    }
+}
 
 /// A map containing a unique, non-resizable array to the Key/Value pairs. If the map needs to be
 /// resized, a new `MapInner` must be created and its Key/Value pairs must be copied from this one.
 /// Logically, this struct owns its keys and values, and so is responsible for freeing them when
 /// dropped.
 #[derive(Debug)]
-pub struct MapInner<'v, K, V: 'v, S = RandomState> {
+pub struct MapInner<'v, K, V: 'v, S = RandomState, A: AtomicKey<K> = BoxedKey<K>> {
     /// The key/value pairs in this map, allocated as an array of pairs.
-    map: Vec<KVPair<'v,K,V>>,
-    /// The amount of key/value pairs in the array, if any.
-    size: AtomicUsize,
-    /// Points to the newer map or null if none.
-    pub(crate) newer_map: AtomicPtr<MapInner<'v,K,V,S>>,
+    ///
+    /// A `MapInner` is only dropped once no other thread can access it, either from a function
+    /// deferred by `promote()` or `AtomicBox`, or by the thread that owns the `LockFreeHashMap`.
+    /// So the slots are freed right away, rather than deferred again. That would need to pin the
+    /// current thread, which panics while an exiting thread runs its deferred functions.
+    map: Arc<Slots<K,V,A>>,
+    /// The amount of key/value pairs in the array, if any. A key/value pair stops being counted
+    /// once it has been copied into the newer map, which counts it instead.
+    ///
+    /// This and the other counters below are written to by many threads, so each of them is on its
+    /// own cache line, away from the fields that are only read.
+    size: CachePadded<StripedCounter>,
+    /// The number of key slots that have been claimed by a key, which never decreases because
+    /// keys are never removed from their slots.
+    key_slots_used: CachePadded<AtomicUsize>,
+    /// Points to the newer map or null if none. It isn't dropped along with this map, because this
+    /// map could have been dropped from `promote()`, after the newer map replaced it.
+    pub(crate) newer_map: AtomicPtr<MapInner<'v,K,V,S,A>>,
     /// Any thread can allocate memory to resize the map and create `newer_map`. Thus, we want to
     /// try and limit the amount of allocations done. This is a monotonically increasing count of
     /// the number of threads currently trying to allocate a new map, which is used as a heuristic.
     /// See its use in the `MapInner::create_newer_map()` function.
-    resizers_count: AtomicUsize,
-    /// The number of `::COPY_CHUNK_SIZE = 32` element chunks that some thread has commited to
-    /// copying to the newer table. Once this reaches `capacity/COPY_CHUNK_SIZE`, we know that the
+    resizers_count: CachePadded<AtomicUsize>,
+    /// The number of `config.copy_chunk_size` element chunks that some thread has commited to
+    /// copying to the newer table. Once this reaches `capacity/copy_chunk_size`, we know that the
     /// entire map has been copied into the large `newer_map`.
-    chunks_copied: AtomicUsize,
+    chunks_copied: CachePadded<AtomicUsize>,
     /// The actual number of key/value pairs that have been copied into the newer map.
-    slots_copied: AtomicUsize,
+    slots_copied: CachePadded<AtomicUsize>,
     /// The hasher used to hash keys.
     hash_builder: S,
+    /// The settings of the map, which are cloned into the `newer_map`.
+    config: Config,
+    /// The values of the map are borrowed for `'v` by `PutValue::Shared`.
+    _values: PhantomData<&'v V>,
 }
 
 
-impl<'v, K, V, S> MapInner<'v, K, V, S> {
+/// The part of `help_copy()` that is the same for every kind of table. Claims up to `max_chunks`
+/// chunks of `chunk_size` slots by incrementing `chunks_copied`, copies each slot of a chunk with
+/// `copy_slot()`, which returns whether this thread was the one to copy it, and then reports the
+/// number of slots it copied to `try_promote()`.
+///
+/// Once every chunk has been claimed, this calls `try_promote()` with 0 instead and returns.
+pub(crate) fn copy_chunks<C, P>(
+    chunks_copied: &AtomicUsize,
+    chunk_size: usize,
+    capacity: usize,
+    max_chunks: usize,
+    mut copy_slot: C,
+    mut try_promote: P,
+)
+    where C: FnMut(usize) -> bool,
+          P: FnMut(usize),
+{
+    /// Checked multiplication that gives an `upper_bound` value if the multiplication exceeds
+    /// the bound (or if overflow occurs).
+    fn checked_times(first: usize, second: usize, upper_bound: usize) -> usize {
+        let result = first * second;
+        if first != 0 && result/first != second {
+            upper_bound
+        } else if result > upper_bound {
+            upper_bound
+        } else {
+            result
+        }
+    }
+    for _ in 0..max_chunks {
+        // `chunks_copied` is an atomic variable that keeps track of which chunk will be copied
+        // into the newer table. It only hands out chunks, so it can be relaxed. The copies
+        // themselves synchronize through the slots and `slots_copied`.
+        let chunks = chunks_copied.fetch_add(1, Ordering::Relaxed);
+        let next_chunk = chunks + 1;
+        // Next find the element-wise lower and upper bounds respectively.
+        let lower_bound = checked_times(chunks, chunk_size, capacity);
+        let upper_bound = checked_times(next_chunk, chunk_size, capacity);
+        debug_assert!(lower_bound <= upper_bound);
+        // If they're equal, then we know another thread incremented `chunks_copied` such that
+        // `(chunks_copied + 1) * chunk_size` will be equal to the size of the current
+        // map. Therefore, we have nothing left to copy and can return.
+        if lower_bound >= upper_bound {
+            // But before we do, we should decrement `chunks_copied` by 1, just to make sure it
+            // won't overflow. It can still overflow if you have `usize::MAX` amount of threads
+            // calling `help_copy`, but it will be assumed that this never happens.
+            chunks_copied.fetch_sub(1, Ordering::Relaxed);
+            // In the rare event that the `newer_map` has finished copying all its elements
+            // into an even `newer_map`, it can call `promote()` and fail, because it's not the
+            // current map. Thus, we call it again here, even if some other thread was
+            // "supposed" to have called it.
+            try_promote(0);
+            return;
+        }
+        let mut slots_copied = 0;
+        // Now because `lower_bound` must be less than `upper_bound`, and since we already
+        // assumed that any thread that gets some `chunks_copied` MUST then copy all elements
+        // in that chunk, we MUST do so.  Notice that the `..upper_bound` is exclusive, so it
+        // never exceeds (capacity - 1).
+        for i in lower_bound..upper_bound {
+            // Now simply copy_slot() for each element in the chunk of the array that we're
+            // assigned.
+            if copy_slot(i) {
+                slots_copied += 1;
+            }
+        }
+        try_promote(slots_copied);
+        if upper_bound == capacity {
+            return;
+        }
+        // Otherwise, there is still more to be done, or at least there was more when we last
+        // checked `chunks_copied`. Keep copying until we've copied `max_chunks` chunks, and
+        // then let some other thread do the rest.
+    }
+}
+
+impl<'v, K, V, S, A: AtomicKey<K>> MapInner<'v, K, V, S, A> {
     /// The default size of a new `LockFreeHashMap`.
     pub const DEFAULT_CAPACITY: usize = ::LockFreeHashMap::<(), (), RandomState>::DEFAULT_CAPACITY;
 
+    /// The minimum number of key slots to probe before giving up. See `MapInner::reprobe_limit()`.
+    pub const REPROBE_LIMIT: usize = 10;
+
     /// Returns the capacity of the current map; i.e. the length of the `Vec` storing the key/value
     /// pairs.
     pub fn capacity(&self) -> usize {
-        self.map.capacity()
+        self.map.len()
+    }
+
+    /// Returns the maximum number of key slots to probe when looking for a key, i.e.
+    /// `REPROBE_LIMIT + capacity/4`. Because a key is never inserted further than this from its
+    /// initial index, a lookup can stop probing here, and an insert resizes the map instead.
+    pub fn reprobe_limit(&self) -> usize {
+        Self::REPROBE_LIMIT + (self.capacity() >> 2)
+    }
+
+    /// Returns the indices to probe for a key that hashes to `initial_index`, wrapping around the
+    /// end of the map and stopping at the reprobe limit.
+    fn probe_indices(&self, initial_index: usize) -> ::std::iter::Take<
+        ::std::iter::Chain<::std::ops::Range<usize>, ::std::ops::Range<usize>>
+    > {
+        (initial_index..self.capacity()).chain(0..initial_index).take(self.reprobe_limit())
     }
 
     /// Returns the size of the current map at some point in time; i.e. the number of key/value
     /// pairs in the map.
     pub fn len(&self) -> usize {
-        self.size.load(Ordering::SeqCst)
+        Self::clamp_len(self.size.sum())
+    }
+
+    /// Returns an estimate of the size of the current map, which is faster than `len()`. See
+    /// `StripedCounter::estimate()`.
+    pub fn len_estimate(&self) -> usize {
+        Self::clamp_len(self.size.estimate())
     }
 
-    pub fn get_at(&self, pos: usize) -> Option<&KVPair<'v, K, V>> {
+    /// Sums `count` over the size of the map in `outer_map` and the sizes of all of its newer
+    /// maps, without helping to copy them.
+    ///
+    /// The thread that copies a key/value pair into a newer map decrements the size of the older
+    /// map, so the size of an older map can be negative. The sizes are summed before clamping,
+    /// so that this cancels out with the increment in the newer map.
+    pub fn sum_over_newer_maps<F>(outer_map: &AtomicBox<Self>, count: F, guard: &Guard)
+        -> usize
+        where F: Fn(&StripedCounter) -> isize
+    {
+        let mut inner = outer_map.load(guard).deref();
+        let mut sum = count(&inner.size);
+        while let Some(newer_map) = inner.newer_map.load(guard).as_option() {
+            inner = newer_map.deref();
+            sum = sum.wrapping_add(count(&inner.size));
+        }
+        Self::clamp_len(sum)
+    }
+
+    /// Converts a sum of `StripedCounter`s to a length. A sum that is read while other threads
+    /// insert and remove keys can be negative, which is clamped to 0, like Dr. Click's `size()`.
+    fn clamp_len(sum: isize) -> usize {
+        ::std::cmp::max(sum, 0) as usize
+    }
+
+    pub fn get_at(&self, pos: usize) -> Option<&KVPair<A, V>> {
         self.map.get(pos)
     }
 
+    /// Takes ownership of the key/value pair at index `pos`, leaving the slot empty. Returns None
+    /// if there was no key with a `ValueSlot::Value` there.
+    ///
+    /// # Unsafe
+    /// This is unsafe because no other thread can have access to this map. In addition, this map
+    /// can't have a `newer_map`, which would share ownership of the keys and values.
+    pub unsafe fn take_at(&self, pos: usize, guard: &Guard) -> Option<(K, V)> {
+        debug_assert!(!self.newer_map.relaxed_exists(guard));
+        let (k, v) = self.get_at(pos)?;
+        // Empty slots and keys without a value are just dropped here. Tombstones and shared values
+        // are sentinels, so they're left in the slot.
+        let value = match v.load(guard).as_option() {
+            Some(value) if !self.map.sentinels.contains_value(value.deref()) => v.take(guard),
+            _ => None,
+        };
+        match (k.take(guard), value.map(|v| *v)) {
+            (Some(key), Some(ValueSlot::Value(value))) => {
+                self.size.decrement();
+                Some((key, value))
+            },
+            _ => None,
+        }
+    }
+
     /// Drops `self.newer_map` and any newer maps that `self.newer_map` points to.
     pub unsafe fn drop_newer_maps(&self, guard: &Guard) {
         if let Some(newer_map) = self.newer_map.take(guard) {
@@ -410,6 +756,16 @@ impl<'v, K, V, S> MapInner<'v, K, V, S> {
         }
     }
 
+    /// Drops the newer maps of the map in `outer_map`, when `outer_map` is about to be dropped.
+    /// Dropping the `AtomicBox` only drops the map that it points to.
+    pub fn drop_newer_maps_in(outer_map: &mut AtomicBox<Self>) {
+        let guard = ::pin();
+        // No other thread can access the maps, since `outer_map` is borrowed mutably.
+        unsafe {
+            outer_map.load(&guard).deref().drop_newer_maps(&guard);
+        }
+    }
+
     /// Drops self, `self.newer_map` and any newer maps that `self.newer_map` points to.
     pub unsafe fn drop_self_and_newer_maps(self, guard: &Guard) {
         let newer_map = self.newer_map.take(guard);
@@ -427,96 +783,107 @@ impl<'v, K: Hash + Eq, V: PartialEq> MapInner<'v,K,V,RandomState> {
     }
 }
 
-impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
+impl<'guard, 'v: 'guard, K, V, S, A: AtomicKey<K>> MapInner<'v, K,V,S,A>
     where K: Hash + Eq,
           V: PartialEq,
           S: BuildHasher + Clone,
 {
+    /// Loads the map in `outer_map`, after helping to finish copying any newer maps. The returned
+    /// map has no newer map at the point in time it was loaded.
+    pub fn load_newest(outer_map: &AtomicBox<Self>, guard: &'guard Guard) -> &'guard Self {
+        let mut inner = outer_map.load(guard);
+        while let Some(newer_map) = inner.newer_map.load(guard).as_option() {
+            inner.help_copy(newer_map, usize::MAX, outer_map, guard);
+            inner = outer_map.load(guard);
+        }
+        inner.deref()
+    }
+
     pub fn with_capacity_and_hasher(size: usize, hasher: S) -> Self {
+        MapInner::with_config(size, hasher, Config::default())
+    }
+
+    pub fn with_config(size: usize, hasher: S, config: Config) -> Self {
+        MapInner::with_sentinels(size, hasher, config, Arc::new(Sentinels::new()))
+    }
+
+    /// Creates a `MapInner` whose keys can all share `value`, by putting `shared_value()`.
+    pub fn with_shared_value(size: usize, hasher: S, config: Config, value: V) -> Self {
+        MapInner::with_sentinels(size, hasher, config, Arc::new(Sentinels::with_value(value)))
+    }
+
+    fn with_sentinels(size: usize, hasher: S, config: Config, sentinels: Arc<Sentinels<K, V>>)
+        -> Self
+    {
         let size = usize::checked_next_power_of_two(size).unwrap_or(Self::DEFAULT_CAPACITY);
-        let mut map = Vec::with_capacity(size);
-        for _ in 0..size {
-            map.push((AtomicPtr::new(None), AtomicPtr::new(None)));
-        }
         MapInner {
-            map: map,
-            size: AtomicUsize::new(0),
+            map: Arc::new(Slots::new(size, config.table_layout, sentinels)),
+            size: CachePadded(StripedCounter::new(size)),
+            key_slots_used: CachePadded::default(),
             newer_map: AtomicPtr::new(None),
-            resizers_count: AtomicUsize::new(0),
-            chunks_copied: AtomicUsize::new(0),
-            slots_copied: AtomicUsize::new(0),
+            resizers_count: CachePadded::default(),
+            chunks_copied: CachePadded::default(),
+            slots_copied: CachePadded::default(),
             hash_builder: hasher,
+            config,
+            _values: PhantomData,
+        }
+    }
+
+    /// Creates an empty `MapInner` of a given size, with the same hasher, settings and sentinels
+    /// as `self`.
+    pub fn with_capacity_from(&self, size: usize) -> Self {
+        MapInner::with_sentinels(
+            size, self.hash_builder.clone(), self.config.clone(), self.map.sentinels.clone()
+        )
+    }
+
+    /// Returns a pointer to the shared `ValueSlot` sentinel for `state`, which must not be a
+    /// `ValueSlot::Value`. The sentinels live as long as any map that uses them.
+    fn sentinel(&self, state: ValueSlot<V>) -> NotNull<'v, ValueSlot<V>> {
+        let sentinel: &ValueSlot<V> = self.map.sentinels.get(state);
+        NotNull::from_ref(unsafe { &*(sentinel as *const _) })
+    }
+
+    /// Returns a pointer to the `ValueSlot::Value` that every key of this map shares, which is
+    /// never freed by a value slot. Only a map created with `with_shared_value()` has one.
+    pub fn shared_value(&self) -> NotNull<'v, ValueSlot<V>> {
+        let sentinel: &ValueSlot<V> = self.map.sentinels.value.as_ref()
+            .expect("Only a map created with `with_shared_value()` has a shared value");
+        NotNull::from_ref(unsafe { &*(sentinel as *const _) })
+    }
+
+    /// Returns a pointer to the shared `KeySlot::SeeNewTable` sentinel.
+    fn key_sentinel(&self) -> NotNull<'guard, KeySlot<K>> {
+        NotNull::from_ref(unsafe { &*(&*self.map.sentinels.key_see_new_table as *const _) })
+    }
+
+    /// Returns an estimate of the state of this map, as used by its [ResizePolicy].
+    pub fn resize_stats(&self) -> ResizeStats {
+        ResizeStats {
+            capacity: self.capacity(),
+            len: self.len_estimate(),
+            key_slots_used: self.key_slots_used.load(Ordering::Relaxed),
         }
     }
 
-    /// Help copy a small chunk of the map to the `newer_map`. See `::COPY_CHUNK_SIZE` for the
-    /// default chunk size.
+    /// Help copy up to `max_chunks` chunks of the map to the `newer_map`, or until there are no
+    /// chunks left to copy. See `::COPY_CHUNK_SIZE` for the default chunk size.
     pub fn help_copy(
         &self,
         newer_map: NotNull<Self>,
-        copy_everything: bool,
+        max_chunks: usize,
         outer_map: &AtomicBox<Self>,
         guard: &'guard Guard,
     ) {
-        /// Checked multiplication that gives an `upper_bound` value if the multiplication exceeds
-        /// the bound (or if overflow occurs).
-        fn checked_times(first: usize, second: usize, upper_bound: usize) -> usize {
-            let result = first * second;
-            if first != 0 && result/first != second {
-                upper_bound
-            } else if result > upper_bound {
-                upper_bound
-            } else {
-                result
-            }
-        }
-        loop {
-            // `chunks_copied` is an atomic variable that keeps track of which chunk will be copied
-            // into the newer table.
-            let chunks_copied = self.chunks_copied.fetch_add(1, Ordering::SeqCst);
-            let next_chunk = chunks_copied + 1;
-            // Next find the element-wise lower and upper bounds respectively.
-            let lower_bound = checked_times(chunks_copied, ::COPY_CHUNK_SIZE, self.capacity());
-            let upper_bound = checked_times(next_chunk, ::COPY_CHUNK_SIZE, self.capacity());
-            debug_assert!(lower_bound <= upper_bound);
-            // If they're equal, then we know another thread incremented `chunks_copied` such that
-            // `(chunks_copied + 1) * COPY_CHUNK_SIZE` will be equal to the size of the current
-            // map. Therefore, we have nothing left to copy and can return.
-            if lower_bound >= upper_bound {
-                // But before we do, we should decrement `chunks_copied` by 1, just to make sure it
-                // won't overflow. It can still overflow if you have `usize::MAX` amount of threads
-                // calling `help_copy`, but it will be assumed that this never happens.
-                self.chunks_copied.fetch_sub(1, Ordering::SeqCst);
-                // In the rare event that the `newer_map` has finished copying all its elements
-                // into an even `newer_map`, it can call `promote()` and fail, because it's not the
-                // current map. Thus, we call it again here, even if some other thread was
-                // "supposed" to have called it.
-                self.try_promote(newer_map, 0, outer_map, guard);
-                return;
-            }
-            let mut slots_copied = 0;
-            // Now because `lower_bound` must be less than `upper_bound`, and since we already
-            // assumed that any thread that gets some `chunks_copied` MUST then copy all elements
-            // in that chunk, we MUST do so.  Notice that the `..upper_bound` is exclusive, so it
-            // never exceeds (self.capacity() - 1).
-            for i in lower_bound..upper_bound {
-                // Now simply copy_slot() for each element in the chunk of the array that we're
-                // assigned.
-                if self.copy_slot(&*newer_map, i, outer_map, guard) {
-                    slots_copied += 1;
-                }
-            }
-            self.try_promote(newer_map, slots_copied, outer_map, guard);
-            if upper_bound == self.capacity() {
-                return;
-            } else if !copy_everything {
-                // Otherwise, we did not copy everything and there is still more to be done,
-                // or at least there was more when we last checked `chunks_copied`. If we are not
-                // required to copy everything, then just return and let some other thread do it.
-                return;
-            }
-            // Otherwise, continue the loop and keep copying until everything is copied.
-        }
+        copy_chunks(
+            &self.chunks_copied,
+            self.config.copy_chunk_size,
+            self.capacity(),
+            max_chunks,
+            |index| self.copy_slot(&*newer_map, index, outer_map, guard),
+            |slots_copied| { self.try_promote(newer_map, slots_copied, outer_map, guard); },
+        );
     }
 
     /// Once a `MapInner` has had all its elements copied to its `newer_map` field,
@@ -533,11 +900,11 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
         // manually from `outer_map`, which must be passed as a parameter throughout various
         // function calls...
         let current_map_shared: NotNull<_> = outer_map.load(guard);
-        let current_map: &MapInner<_,_,_> = &*current_map_shared;
+        let current_map: &Self = &current_map_shared;
         // This appears to be that some other thread already promoted us, or the rare event in
         // which we called `promote()` before the previous map called `promote()`.
         // Just return here.
-        if current_map as *const _ != self as *const _ {
+        if !ptr::eq(current_map, self) {
             return false;
         }
         match outer_map.compare_and_set_shared(current_map_shared, new_map, guard) {
@@ -545,6 +912,12 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                 // We successfully swapped the value of the `AtomicBox` and are therefore
                 // responsible for freeing the old map's memory.
                 unsafe { guard.defer(move || current_map_shared.as_shared().into_owned()); }
+                // `new_map` could have been copied entirely into its own newer map before it was
+                // promoted, in which case its `try_promote()` failed. Promote that map now, so
+                // that the map doesn't stay on `new_map` until some thread helps copy it again.
+                if let Some(newer_map) = new_map.newer_map.load(guard).as_option() {
+                    new_map.try_promote(newer_map, 0, outer_map, guard);
+                }
                 return true;
             },
             Err((_current, _)) => {
@@ -565,9 +938,9 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
         let newer_map_shared = self.newer_map.load(&guard);
         if let Some(new_map) = newer_map_shared.as_option() {
             if self.copy_slot(&*new_map, copy_index, outer_map, guard) {
-                self.slots_copied.fetch_add(1, Ordering::SeqCst);
+                self.slots_copied.fetch_add(1, RMW_ORDERING);
             }
-            self.help_copy(new_map, false, outer_map, guard);
+            self.help_copy(new_map, self.config.copy_budget.unwrap_or(1), outer_map, guard);
             new_map
         } else {
             unreachable!("can't call ensure_slot_copied() unless found a prime value");
@@ -582,7 +955,9 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
         guard: &'guard Guard
     ) -> bool
     {
-        let previous_slots_copied = self.slots_copied.fetch_add(current_slots_copied, Ordering::SeqCst);
+        // The thread that adds the last slot acquires the copies of all the other threads, which
+        // released them with their own additions, before promoting the newer map.
+        let previous_slots_copied = self.slots_copied.fetch_add(current_slots_copied, RMW_ORDERING);
         if current_slots_copied > 0 {
         debug_assert!(previous_slots_copied + current_slots_copied <= self.capacity(),
             format!("previous: {} current: {}", previous_slots_copied, current_slots_copied)
@@ -618,41 +993,14 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
             MaybeNull::from_shared(Shared::from(maybe.as_shared().as_raw()))
         }
         let (ref atomic_key_slot, ref atomic_value_slot) = self.map[old_map_index];
-        let old_key: NotNull<_>;
-        let mut new_key = NotNullOwned::new(KeySlot::SeeNewTable);
 
-        // Preemptively set an empty key slot to `SeeNewTable`.
-        loop {
-            let cas_key_result = atomic_key_slot.compare_and_set_owned_weak(
-                MaybeNull::from_shared(Shared::null()), new_key, guard);
-            match cas_key_result {
-                Ok(_new_key) => {
-                    debug_assert!(if let &KeySlot::SeeNewTable = &*_new_key {true} else {false});
-                    return true;
-                },
-                Err((current, new)) => {
-                    new_key = new; // Return ownership
-                    let _old_key_shared = current;
-                    // Because `compare_and_set_weak()` can spuriously fail and therefore still be
-                    // null. Thus, just retry with `continue` if it's still null.
-                    match current.as_option() {
-                        // No one updated the key slot from `empty` to something else, due to using
-                        // a weak version of CAS here. Thus, we can try again.
-                        None => continue,
-                        Some(k) => {
-                            match k.deref() {
-                                &KeySlot::SeeNewTable => return false,
-                                &KeySlot::Key(_) => {
-                                    debug_assert!(current.as_option().is_some());
-                                    old_key = k;
-                                    break;
-                                },
-                            }
-                        }
-                    }
-                },
-            }
-        }
+        // Preemptively set an empty key slot to the `SeeNewTable` sentinel.
+        let old_key = match atomic_key_slot.put_see_new_table(self.key_sentinel(), guard) {
+            Ok(()) => return true,
+            Err(LoadedKey::SeeNewTable) => return false,
+            Err(LoadedKey::Key(k)) => k,
+            Err(LoadedKey::Empty) => unreachable!("The CAS only fails if the key slot isn't empty"),
+        };
 
         // If we got to this point, then we know that there is an existing, non-null key. Thus, we
         // need to do the following state transitions:
@@ -671,19 +1019,19 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
         //         without a value into the newer map.
         // Note that also both operations in transition [2] can happen on two different threads.
         // In addition, care needs to be taken for the rest of this function to ensure that we
-        // (defer) drop destructors that we need to, but only once.
+        // (defer) drop destructors that we need to, but only once. None of the transitions
+        // allocate, because V' is a tagged pointer to V, and X is a sentinel.
         let mut old_value: MaybeNull<_> = cheat_lifetime(atomic_value_slot.load(guard));
-        let not_null_old_value: NotNull<_>;
         let primed_old_value: NotNull<ValueSlot<_>>;
         let mut original_valueslot_value = None;
 
         loop {
             match old_value.as_option() {
-                // Swap `None`/`Null` values with `SeeNewTable`.
+                // Swap `None`/`Null` values with `EmptySeeNewTable`.
                 None => {
-                    match atomic_value_slot.compare_and_set_owned(
+                    match atomic_value_slot.compare_and_set(
                         MaybeNull::from_shared(Shared::null()),
-                        NotNullOwned::new(ValueSlot::SeeNewTable),
+                        self.sentinel(ValueSlot::EmptySeeNewTable),
                         guard,
                     ) {
                         Err((current, _)) => {
@@ -699,14 +1047,19 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                         },
                     }
                 },
+                // Some other thread already did (K, V) -> (K, V'), so help it finish the copy.
+                Some(not_null) if not_null.is_valueprime() => {
+                    primed_old_value = not_null;
+                    break;
+                },
                 // Otherwise we have a `ValueSlot` here. Let's take a little peek inside.
                 Some(not_null) => match not_null.deref() {
                     // Some other thread copied the slot already. Nothing to do or free here.
-                    &ValueSlot::SeeNewTable  => return false,
+                    &ValueSlot::SeeNewTable | &ValueSlot::EmptySeeNewTable => return false,
                     &ValueSlot::Tombstone => {
-                        match atomic_value_slot.compare_and_set_owned(
+                        match atomic_value_slot.compare_and_set(
                             old_value,
-                            NotNullOwned::new(ValueSlot::SeeNewTable),
+                            self.sentinel(ValueSlot::SeeNewTable),
                             guard,
                         ) {
                             Err((current, _)) => {
@@ -717,25 +1070,21 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                                 continue;
                             },
                             Ok(_new) => {
-                                // Successfully did (K, T) -> (K, X). Remember that `old_value`
-                                // here is just the atomic pointer to the tombstone. However, all
-                                // `ValueSlot`s are behind pointers and therefore need to be freed.
+                                // Successfully did (K, T) -> (K, X). The tombstone is a sentinel,
+                                // so there's nothing to free.
                                 debug_assert!(_new.is_seenewtable());
-                                unsafe { guard.defer(move || not_null.drop()); }
                                 return true;
                             }
                         }
                     },
                     // There's a value here. So (K, V) -> (K, V') needs to happen.
                     &ValueSlot::Value(_) => {
-                        // old_value was `Value` and not `ValuePrime`.
-                        let primed_old_value_owned = NotNullOwned::new(ValueSlot::ValuePrime(not_null.deref()));
-                        match atomic_value_slot.compare_and_set_owned(
+                        match atomic_value_slot.compare_and_set(
                             old_value, // `ValueSlot::Value(_)`
-                            primed_old_value_owned,
+                            not_null.with_tag(),
                             guard
                         ) {
-                            Err((current, _dropped_because_owned)) => {
+                            Err((current, _)) => {
                                 debug_assert!(current.as_option().is_some());
                                 old_value = cheat_lifetime(current);
                                 continue;
@@ -749,41 +1098,27 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                                 // store the `ValueSlot` that we need to free in this variable.
                                 original_valueslot_value = Some(not_null);
                                 debug_assert!(shared_primed_value.is_valueprime());
-                                not_null_old_value = not_null;
-                                primed_old_value = shared_primed_value;
+                                primed_old_value = not_null.with_tag();
                                 break;
                             },
                         }
                     }
-                    &ValueSlot::ValuePrime(_)  => {
-                        not_null_old_value = not_null;
-                        primed_old_value = not_null;
-                        break;
-                    }
                 }
             }
         }
 
         // If we have gotten this far, then we know that at least the first transition has
-        // occurred, i.e. (K, V) -> (K, V').
-        // Also, `not_null_old_value` and `primed_old_value` are assigned, with
-        // `not_null_old_value` being either `Value` or `ValuePrime`.
-        debug_assert!(not_null_old_value.is_value() || not_null_old_value.is_valueprime());
-        debug_assert!(primed_old_value.is_valueprime());
-
-        // Now, we know that `old_value` must be either V or V', depending on whether
-        // `not_null` was a `ValueSlot::Value(_)` or `ValueSlot::ValuePrime(_)`.
-        let put_value = match not_null_old_value.deref() {
-            &ValueSlot::Value(_) => PutValue::Shared(not_null_old_value),
-            &ValueSlot::ValuePrime(v) => match v {
-                &ValueSlot::Value(_) =>
-                    PutValue::Shared(MaybeNull::from_shared(Shared::from(v as *const _))
-                        .as_option().expect("v is a reference and can't be null")
-                    ),
-                _ => unreachable!("`ValuePrime` can only be a reference to a `Value`"),
-            }
-            &ValueSlot::Tombstone => unreachable!(),
-            &ValueSlot::SeeNewTable => unreachable!(),
+        // occurred, i.e. (K, V) -> (K, V'). Dereferencing V' ignores the tag, so it gives V.
+        debug_assert!(primed_old_value.is_valueprime() && primed_old_value.deref().is_value());
+        let put_value = PutValue::Shared(NotNull::from_ref(primed_old_value.deref()));
+        // Reuse the hash of the key, so that it doesn't need to be hashed again. It's loaded with
+        // `Relaxed`, but `put_if_match()` stores it before putting V, and V' continues the release
+        // sequence of V, so loading V or V' above makes the stored hash visible. It's only 0 if the
+        // hash really is 0 or the key slots don't store hashes, and then the key is hashed again.
+        // See `model::test_copy_reads_stored_hash()`.
+        let hash = match atomic_key_slot.stored_hash() {
+            0 => self.hash_key(A::shared_key(&old_key)),
+            hash => hash,
         };
         // Now we try to copy the original value into the newer map, but only if there is
         // no value in there already. If this fails, then it was copied and/or updated in
@@ -791,28 +1126,33 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
         //
         // We copied the key/value pair into the new map if the previous value associated
         // with the key `is_none()`.
-        let copied_into_new = new_map.put_if_match(
-            KeyCompare::Shared(old_key),
+        let copied_into_new = new_map.put_if_match_with_hash(
+            hash,
+            KeyCompare::<K, K, A>::Shared(old_key),
             put_value,
             Match::Empty,
             outer_map,
             guard
         ).is_none();
         if copied_into_new {
-            debug_assert!(!atomic_key_slot.is_tagged(guard));
-            debug_assert!(!atomic_value_slot.is_tagged(guard));
+            // The key slot could already be tagged by the thread that published this key, if it
+            // also published the key into the newer map. See `put_if_match_in_newer_map()`.
             atomic_key_slot.tag(guard);
             debug_assert!(atomic_key_slot.is_tagged(guard));
         }
 
-        // Now we simply need to just do (K, V') -> (K, X).
-        let primed_old_value_maybe: MaybeNull<_> = primed_old_value.as_maybe_null();
-        match atomic_value_slot.compare_and_set_owned(
-            primed_old_value_maybe, NotNullOwned::new(ValueSlot::SeeNewTable), guard
+        // Now we simply need to just do (K, V') -> (K, X). Exactly one thread does this
+        // transition, so that thread is the one that reports the slot as copied. Whether or not
+        // it was also the thread that `copied_into_new` doesn't matter.
+        let finished_copy = match atomic_value_slot.compare_and_set(
+            primed_old_value.as_maybe_null(), self.sentinel(ValueSlot::SeeNewTable), guard
         ) {
             Ok(_current) => {
                 debug_assert!(_current.is_seenewtable());
-                unsafe { primed_old_value_maybe.try_defer_drop(guard); }
+                // The newer map counts the key/value pair now, so that the sizes of this map and
+                // its newer maps can be summed without finishing the copy.
+                self.size.decrement();
+                true
             },
             Err((current, _)) => {
                 debug_assert!(current.as_option()
@@ -820,96 +1160,195 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                     .unwrap_or(false),
                     "can't be null again"
                 );
+                false
             },
-        }
-        // This is only `Some` if we are the thread that did (K, V) -> (K, V').
+        };
+        // This is only `Some` if we are the thread that did (K, V) -> (K, V'). A shared value is a
+        // sentinel, so it's never freed.
+        let original_valueslot_value = original_valueslot_value
+            .filter(|value| !self.map.sentinels.contains_value(value.deref()));
         if let Some(original_value) = original_valueslot_value {
+            let slots = self.map.clone();
             unsafe { guard.defer(move || {
                 // We only want to drop this value if it was never copied to the new map.
-                if !atomic_key_slot.is_tagged(guard) {
+                if !slots[old_map_index].0.is_tagged(::crossbeam_epoch::unprotected()) {
                     original_value.drop();
                 }
             })}
         }
-        return copied_into_new;
+        return finished_copy;
     }
 
-    /// If `newer_map` doesn't exist, then this function tries to allocate a newer map that's
-    /// double the size of `self`.
+    /// Returns the smallest capacity that can hold `len` key/value pairs while keeping the map at
+    /// most half full.
+    pub fn min_capacity_for(len: usize) -> usize {
+        len.saturating_mul(2).checked_next_power_of_two().unwrap_or(len)
+    }
+
+    /// Tries to copy this map into a smaller newer map, that can hold at least `capacity` keys as
+    /// well as every live key/value pair. Then, helps copy the entire map into it.
     ///
-    /// Returns a `Shared` pointer to the newer map
-    pub fn create_newer_map(&self, guard: &'guard Guard) -> NotNull<'guard, Self>
+    /// Returns false if the map is already small enough, or if some other thread already created
+    /// a newer map.
+    pub fn shrink_to(&self, capacity: usize, outer_map: &AtomicBox<Self>, guard: &'guard Guard)
+        -> bool
     {
-        fn try_double(current_size: usize) -> usize {
-            let doubled_size = current_size << 1;
-            if doubled_size < current_size {
-                current_size
-            } else {
-                doubled_size
+        self.shrink_to_with_budget(capacity, usize::MAX, outer_map, guard)
+    }
+
+    /// Like `shrink_to()`, but only helps copy up to `max_chunks` chunks into the newer map. The
+    /// rest is copied by the operations that find the newer map later.
+    fn shrink_to_with_budget(
+        &self,
+        capacity: usize,
+        max_chunks: usize,
+        outer_map: &AtomicBox<Self>,
+        guard: &'guard Guard,
+    ) -> bool {
+        let new_capacity = ::std::cmp::max(capacity, Self::min_capacity_for(self.len()));
+        match new_capacity.checked_next_power_of_two() {
+            Some(c) if c < self.capacity() => (),
+            _ => return false,
+        }
+        self.resize_to(new_capacity, max_chunks, outer_map, guard)
+    }
+
+    /// Returns whether the map can hold `additional` more key/value pairs while staying at most
+    /// half full. If not, then tries to copy this map into a newer map that can, and helps copy
+    /// the entire map into it.
+    ///
+    /// Returns false if some other thread already created a newer map, in which case the newer
+    /// map should be checked instead. Panics if the new capacity overflows `usize`.
+    pub fn reserve(&self, additional: usize, outer_map: &AtomicBox<Self>, guard: &'guard Guard)
+        -> bool
+    {
+        let new_capacity = self.len().saturating_add(additional)
+            .checked_mul(2)
+            .and_then(usize::checked_next_power_of_two)
+            .expect("capacity overflow");
+        if new_capacity <= self.capacity() {
+            return true;
+        }
+        self.resize_to(new_capacity, usize::MAX, outer_map, guard)
+    }
+
+    /// Tries to create a newer map with the given capacity, then helps copy up to `max_chunks`
+    /// chunks into it. Returns false if some other thread already created a newer map.
+    fn resize_to(
+        &self,
+        capacity: usize,
+        max_chunks: usize,
+        outer_map: &AtomicBox<Self>,
+        guard: &'guard Guard,
+    ) -> bool {
+        match self.newer_map.compare_null_and_set_owned(
+            NotNullOwned::new(self.with_capacity_from(capacity)),
+            guard
+        ) {
+            Ok(newer_map) => {
+                self.help_copy(newer_map, max_chunks, outer_map, guard);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Shrinks the map if the map was configured to shrink automatically and the number of live
+    /// key/value pairs is below the configured threshold. This is called after every removal.
+    ///
+    /// Unlike `shrink_to()`, the removal that shrinks the map only copies as much as any other
+    /// operation, i.e. the map's copy budget or one chunk. The operations after it copy the rest.
+    /// So only a map that has been promoted can shrink, because the size of a map that is still
+    /// being copied into doesn't include the key/value pairs that haven't been copied yet.
+    fn maybe_auto_shrink(&self, outer_map: &AtomicBox<Self>, guard: &'guard Guard) {
+        if let Some(min_load) = self.config.auto_shrink {
+            let capacity = self.capacity();
+            if capacity > Self::DEFAULT_CAPACITY
+                && (self.len_estimate() as f64) < capacity as f64 * min_load
+                && !self.newer_map.relaxed_exists(guard)
+                && ptr::eq(outer_map.load(guard).deref(), self)
+            {
+                let max_chunks = self.config.copy_budget.unwrap_or(1);
+                self.shrink_to_with_budget(Self::DEFAULT_CAPACITY, max_chunks, outer_map, guard);
             }
         }
+    }
+
+    /// If `newer_map` doesn't exist, then this function tries to allocate a newer map, with the
+    /// capacity decided by the map's [ResizePolicy](::ResizePolicy).
+    ///
+    /// Returns a `Shared` pointer to the newer map
+    pub fn create_newer_map(&self, guard: &'guard Guard) -> NotNull<'guard, Self>
+    {
         let newer_map: MaybeNull<Self> = self.newer_map.load(guard);
         if let Some(not_null) = newer_map.as_option() {
             return not_null;
         }
-        let size = self.capacity();
-        let mut new_size = size;
-        // Double size if map is >25% full
-        if size > (self.capacity() >> 2) {
-            new_size = try_double(new_size);
-            // Double size if map is >50% full
-            if size > (self.capacity() >> 1) {
-                new_size = try_double(new_size);
-            }
-        }
-        let array_element_byte_size: usize = ::std::mem::size_of::<KVPair<K,V>>();
-        // This doesn't need to be accurate, so it can be wrapping to ensure it never panics.
-        let Wrapping(size_in_megabytes)
-            = (Wrapping(array_element_byte_size) * Wrapping(size)) >> (2^10 * 2^10);
-        let current_resizers = self.resizers_count.fetch_add(1, Ordering::SeqCst);
-        if current_resizers >= 2 && size_in_megabytes > 0 {
-            let newer_map: MaybeNull<Self> = self.newer_map.load(guard);
-            if let Some(not_null) = newer_map.as_option() {
-                return not_null;
-            }
-            ::std::thread::sleep(Duration::from_millis(size_in_megabytes as u64));
+        let stats = self.resize_stats();
+        // Always leave room for the live key/value pairs, whatever the policy returns.
+        let new_size = ::std::cmp::max(
+            self.config.resize_policy.new_capacity(&stats),
+            stats.len.saturating_add(1)
+        );
+        // If another thread is already resizing, give it a chance to allocate the newer map,
+        // rather than allocating yet another big map that will just be dropped.
+        let current_resizers = self.resizers_count.fetch_add(1, Ordering::Relaxed);
+        if current_resizers >= 1 {
+            let size_in_bytes = Slots::<K, V, A>::pair_size(self.config.table_layout)
+                .saturating_mul(new_size.checked_next_power_of_two().unwrap_or(new_size));
+            self.config.resize_contention.wait(size_in_bytes >> 20, || {
+                self.newer_map.relaxed_exists(guard)
+            });
         }
         let newer_map: MaybeNull<Self> = self.newer_map.load(guard);
         if let Some(not_null) = newer_map.as_option() {
             return not_null;
         }
-        debug_assert!(new_size >= self.capacity());
         match self.newer_map.compare_null_and_set_owned(
-            NotNullOwned::new(Self::with_capacity_and_hasher(new_size, self.hash_builder.clone())),
+            NotNullOwned::new(self.with_capacity_from(new_size)),
             guard
         ) {
             Ok(shared_newer_map) => {
                 shared_newer_map
             },
             Err((current, _drop_our_map)) => {
-                debug_assert!((&*current as *const _) != (self as *const _));
+                debug_assert!(!ptr::eq(&*current, self));
                 current
             },
         }
     }
 
-    pub fn hash_key<Q: ?Sized>(&self, key: &Q) -> usize
-        where K: Borrow<Q>,
-              Q: Hash + Eq,
-    {
+    /// Returns the full hash of a key. Newer maps use a clone of the same hasher, so this is the
+    /// same in every map.
+    pub fn hash_key<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
         let mut hasher = self.hash_builder.build_hasher();
         key.hash(&mut hasher);
-        // Assumes usize <= u64
-        let hash = hasher.finish() as usize;
+        hasher.finish()
+    }
+
+    /// Returns the index of the first key slot to probe for a key with the given full hash.
+    fn initial_index(&self, hash: u64) -> usize {
         // Since the len()/capacity() of the map is always a power of two, we can use a bitwise-and
         // operation
-        hash & (self.capacity() - 1)
+        (hash as usize) & (self.capacity() - 1)
     }
 
-    pub fn keys_are_equal<T1: ?Sized, T2: ?Sized>(&self, first: &T1, second: &T2) -> bool
-        where T2: PartialEq<T1>,
-    {
-        second == first
+    /// Returns false if the key at index `index` definitely doesn't have the full hash `hash`,
+    /// i.e. the keys don't need to be compared.
+    fn hash_may_match(&self, index: usize, hash: u64) -> bool {
+        let stored_hash = self.map[index].0.stored_hash();
+        stored_hash == 0 || stored_hash == hash
+    }
+
+    /// Stores the full hash of the key at index `index`, if it isn't stored already. This is safe
+    /// to call from any thread that knows the key's hash, because they all store the same hash.
+    fn store_hash(&self, index: usize, hash: u64) {
+        self.map[index].0.store_hash(hash);
+    }
+
+    /// Returns true if `key` is equivalent to `current_key`, a key in the map.
+    pub fn keys_are_equal<Q: ?Sized + Equivalent<K>>(&self, key: &Q, current_key: &K) -> bool {
+        key.equivalent(current_key)
     }
 
     /// Returns the current value associated with some key, if any.
@@ -919,51 +1358,108 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
         outer_map: &AtomicBox<Self>,
         guard: &'guard Guard
     ) -> Option<&'guard V>
-        where K: Borrow<Q>,
-              Q: Hash + Eq + PartialEq<K>,
+        where K: 'guard,
+              Q: Hash + Equivalent<K>,
+    {
+        self.get_key_value(key, outer_map, guard).map(|(_, v)| v)
+    }
+
+    /// Returns the key in the map that is equal to `key`, along with its current associated value,
+    /// if any.
+    pub fn get_key_value<Q>(
+        &self,
+        key: &Q,
+        outer_map: &AtomicBox<Self>,
+        guard: &'guard Guard
+    ) -> Option<(A::Ref<'guard>, &'guard V)>
+        where K: 'guard,
+              Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get_key_value_with_hash(self.hash_key(key), key, outer_map, guard)
+    }
+
+    /// Same as `get_key_value()`, but with the full hash of `key` already computed.
+    fn get_key_value_with_hash<Q>(
+        &self,
+        hash: u64,
+        key: &Q,
+        outer_map: &AtomicBox<Self>,
+        guard: &'guard Guard
+    ) -> Option<(A::Ref<'guard>, &'guard V)>
+        where K: 'guard,
+              Q: Hash + Equivalent<K> + ?Sized,
     {
         // First we need to find/probe the index of the key.
-        let initial_index = self.hash_key(key);
-        let len = self.capacity();
-        for index in (initial_index..len).chain(0..initial_index) {
+        let initial_index = self.initial_index(hash);
+        for index in self.probe_indices(initial_index) {
             let (ref atomic_key_slot, ref atomic_value_slot) = self.map[index];
-            // Early exit if neither the key nor value are fully inserted.
-            if !atomic_key_slot.relaxed_exists(&guard) || !atomic_value_slot.relaxed_exists(&guard)
-            {
-                return None;
-            }
-            match &*atomic_key_slot.load(&guard).as_option()? {
-                &KeySlot::Key(ref k) => if self.keys_are_equal(k, key) {
-                    match atomic_value_slot.load(&guard).as_option()?.deref() {
-                        &ValueSlot::Value(ref v) => return Some(&v),
-                        &ValueSlot::Tombstone => return None,
-                        // We call ensure_slot_copied() even on `SeeNewTable` because it calls
-                        // try_promote().
-                        &ValueSlot::ValuePrime(_) | &ValueSlot::SeeNewTable => {
-                            return self.ensure_slot_copied(index, outer_map, guard)
-                                .get(key, outer_map, guard)
-                        }
+            match atomic_key_slot.load(guard) {
+                // Early exit if the key slot is empty, because the key would have been inserted
+                // here. A key slot without a value can still belong to a different key, so keep
+                // probing.
+                LoadedKey::Empty => return None,
+                LoadedKey::Key(k) => if self.hash_may_match(index, hash)
+                    && self.keys_are_equal(key, A::shared_key(&k))
+                {
+                    let value = atomic_value_slot.load(&guard).as_option()?;
+                    // We call ensure_slot_copied() even on `SeeNewTable` because it calls
+                    // try_promote().
+                    if value.is_prime() {
+                        return self.ensure_slot_copied(index, outer_map, guard)
+                            .get_key_value_with_hash(hash, key, outer_map, guard)
+                    }
+                    match value.deref() {
+                        &ValueSlot::Value(ref v) => return Some((A::key_ref(k), v)),
+                        _ => return None,
                     }
                 } else {
                     continue
                 },
-                &KeySlot::SeeNewTable => {
+                LoadedKey::SeeNewTable => {
                     return self.newer_map.load(&guard)
                         .as_option()
                         // It is safe to `unwrap()` because a newer table must exist before any
                         // `KeySlot`s are set to `SeeNewTable`.
                         .expect("Can't set `KeySlot` to `SeeNewTable` before setting `newer_map`")
-                        .get(key, outer_map, guard);
+                        .get_key_value_with_hash(hash, key, outer_map, guard);
                 },
             }
         }
-        // We exhausted the entire map, so the value could still be inserted into the newer map
+        // We reached the reprobe limit, so the value could still be inserted into the newer map
         return self.newer_map.load(&guard)
             .as_option()
-            .map(|newer_map| newer_map.get(key, outer_map, guard))
+            .map(|newer_map| newer_map.get_key_value_with_hash(hash, key, outer_map, guard))
             .unwrap_or(None)
     }
 
+    /// Returns the key and value at index `pos`, but only if the key slot is a `KeySlot::Key` and
+    /// the value slot is either a `ValueSlot::Value` or a V'. If the value was
+    /// already copied into the newer map, then the key is looked up in the newer map instead, so
+    /// that iterating over a map that is resized at the same time doesn't miss any keys.
+    pub fn get_key_value_at(&self, pos: usize, outer_map: &AtomicBox<Self>, guard: &'guard Guard)
+        -> Option<(A::Ref<'guard>, &'guard V)>
+    {
+        let (ref k, ref v) = *self.get_at(pos)?;
+        let key = match k.load(guard) {
+            LoadedKey::Key(key) => key,
+            LoadedKey::Empty | LoadedKey::SeeNewTable => return None,
+        };
+        match *v.load(guard).as_option()?.deref() {
+            ValueSlot::SeeNewTable => {
+                // As in `copy_slot()`, the hash was stored before the value that X replaced.
+                let hash = match k.stored_hash() {
+                    0 => self.hash_key(A::shared_key(&key)),
+                    hash => hash,
+                };
+                self.newer_map.load(guard)
+                    .as_option()
+                    .expect("Can't set `ValueSlot` to `SeeNewTable` before setting `newer_map`")
+                    .get_key_value_with_hash(hash, A::shared_key(&key), outer_map, guard)
+            },
+            ref value => ValueSlot::as_inner(Some(value)).map(|v| (A::key_ref(key), v)),
+        }
+    }
+
     /// Increments or decrements the current size of the map, returning the previous value in the
     /// map.
     pub fn update_size_and_defer(
@@ -987,7 +1483,7 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
             false
         };
         if increment {
-            self.size.fetch_add(1, Ordering::SeqCst);
+            self.size.increment();
         }
         // If we did insert a tombstone, then we decremented if the old value was V
         let decrement = if insert_tombstone {
@@ -1002,12 +1498,15 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
             false
         };
         if decrement {
-            self.size.fetch_sub(1, Ordering::SeqCst);
+            self.size.decrement();
         }
         match old_value_slot.as_option() {
             None => None,
             Some(value) => {
-                unsafe { guard.defer(move || { value.drop(); })}
+                // Tombstones and shared values are sentinels, which aren't freed.
+                if !self.map.sentinels.contains_value(value.deref()) {
+                    unsafe { guard.defer(move || { value.drop(); })}
+                }
                 Some(value.deref())
             }
         }
@@ -1015,14 +1514,32 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
 
     pub fn put_if_match<Q>(
         &'guard self,
-        key: KeyCompare<K, Q>,
+        key: KeyCompare<'guard, '_, K, Q, A>,
+        put: PutValue<'v, V>,
+        matcher: Match<V>,
+        outer_map: &AtomicBox<Self>,
+        guard: &'guard Guard
+    ) -> Option<&'guard ValueSlot<V>>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = match key.as_key() {
+            Ok(k) => self.hash_key(k),
+            Err(q) => self.hash_key(q),
+        };
+        self.put_if_match_with_hash(hash, key, put, matcher, outer_map, guard)
+    }
+
+    /// Same as `put_if_match()`, but with the full hash of `key` already computed.
+    fn put_if_match_with_hash<Q>(
+        &'guard self,
+        hash: u64,
+        key: KeyCompare<'guard, '_, K, Q, A>,
         mut put: PutValue<'v, V>,
-        matcher: Match,
+        matcher: Match<V>,
         outer_map: &AtomicBox<Self>,
         guard: &'guard Guard
     ) -> Option<&'guard ValueSlot<V>>
-        where K: Borrow<Q>,
-              Q: Hash + Eq + PartialEq<K> + ?Sized,
+        where Q: Hash + Equivalent<K> + ?Sized,
     {
         /// FIXME: See other cheat_lifetime() FIXME note above
         fn cheat_lifetime<'guard, 'v, V>(maybe: NotNull<'guard, V>) -> NotNull<'v, V> {
@@ -1030,52 +1547,61 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                 .as_option()
                 .expect("parameter was `NotNull` to begin with")
         }
-        let initial_index = self.hash_key(key.as_qref().as_qref2().as_q());
-        let len = self.capacity();
+        if let PutValue::Tombstone = put {
+            put = PutValue::Shared(self.sentinel(ValueSlot::Tombstone));
+        }
+        let initial_index = self.initial_index(hash);
         let mut key_index = None;
         let mut key = key;
         // First we need to find the key slot for the key.
         'find_key_loop:
-        for index in (initial_index..len).chain(0..initial_index) {
-            let atomic_key_slot: &AtomicPtr<KeySlot<K>> = &self.map[index].0;
-            let option_key: Option<_> = atomic_key_slot.load(&guard)
-                .as_option();
-            let current_key: NotNull<KeySlot<K>> = match option_key {
-                Some(existing_key) => existing_key,
-                None => if put.is_tombstone() {
+        for index in self.probe_indices(initial_index) {
+            let atomic_key_slot = &self.map[index].0;
+            let current_key = match atomic_key_slot.load(guard) {
+                LoadedKey::Empty => if put.is_tombstone() {
                     // The key is not taken, so we don't put a Tombstone value here
                     return None;
-                } else if let Match::AnyKeyValuePair = matcher {
+                } else if let Match::AnyKeyValuePair | Match::Value(_) = matcher {
                     // If key is not taken, return None if we weren't going to insert something
                     // anyway
                     return None;
                 } else {
                     match key {
                         KeyCompare::Owned(owned) => {
-                            match atomic_key_slot.compare_null_and_set_owned(owned, guard) {
+                            match atomic_key_slot.put_owned(owned, guard) {
                                 Ok(shared_key) => {
-                                    // TODO: Raise keyslots-used count
+                                    self.key_slots_used.fetch_add(1, Ordering::Relaxed);
                                     key = KeyCompare::Shared(shared_key);
                                     key_index = Some(index);
                                     break 'find_key_loop;
                                 },
-                                Err((not_null, _return)) => {
+                                Err((current, _return)) => {
                                     key = KeyCompare::Owned(_return);
-                                    not_null
+                                    current
+                                },
+                            }
+                        },
+                        KeyCompare::Shared(shared) => {
+                            match atomic_key_slot.put_shared(shared, guard) {
+                                Ok(shared_key) => {
+                                    self.key_slots_used.fetch_add(1, Ordering::Relaxed);
+                                    key = KeyCompare::Shared(shared_key);
+                                    key_index = Some(index);
+                                    break 'find_key_loop;
                                 },
+                                Err(current) => current,
                             }
                         },
-                        KeyCompare::Shared(not_null) => {
-                            match atomic_key_slot.compare_null_and_set(not_null, guard) {
+                        KeyCompare::Reclaimable(shared, inserted) => {
+                            match atomic_key_slot.put_shared(shared, guard) {
                                 Ok(shared_key) => {
+                                    self.key_slots_used.fetch_add(1, Ordering::Relaxed);
+                                    inserted.set(true);
                                     key = KeyCompare::Shared(shared_key);
                                     key_index = Some(index);
                                     break 'find_key_loop;
                                 },
-                                Err((not_null, _return)) => {
-                                    key = KeyCompare::Shared(_return);
-                                    not_null
-                                }
+                                Err(current) => current,
                             }
                         },
                         KeyCompare::OnlyCompare(_) => {
@@ -1085,31 +1611,44 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                         }
                     }
                 },
+                current => current,
             };
-            match &*current_key {
-                &KeySlot::Key(ref current_key) => if self.keys_are_equal(key.as_qref().as_qref2().as_q(), current_key.borrow()) {
+            match current_key {
+                LoadedKey::Key(ref current_key) => if self.hash_may_match(index, hash)
+                    && match key.as_key() {
+                        Ok(k) => self.keys_are_equal(k, A::shared_key(current_key)),
+                        Err(q) => self.keys_are_equal(q, A::shared_key(current_key)),
+                    }
+                {
                     key_index = Some(index);
                     break 'find_key_loop;
                 }, // else continue
-                &KeySlot::SeeNewTable => {
+                LoadedKey::SeeNewTable => {
                     break 'find_key_loop;
                 },
+                LoadedKey::Empty => unreachable!("A key slot never goes back to empty"),
             }
         }
 
         let key_index: usize = match key_index {
             Some(k) => k,
             None => {
-                // We have exhausted the entire probing range, so there are no key slots available
-                // and need to resize.
+                // We have reached the reprobe limit, so there are no key slots available and need
+                // to resize. Unless the map has a copy budget, the thread that finds the map full
+                // copies all of it.
                 let new_table: NotNull<Self> = self.create_newer_map(guard);
-                self.help_copy(new_table, true, outer_map, guard);
-                return new_table.deref().put_if_match(key, put, matcher, outer_map, guard);
+                let max_chunks = self.config.copy_budget.unwrap_or(usize::MAX);
+                self.help_copy(new_table, max_chunks, outer_map, guard);
+                return new_table.deref()
+                    .put_if_match_with_hash(hash, key, put, matcher, outer_map, guard);
             },
         };
 
         // We have now found the key slot to use. This key slot will never change now so we know
-        // that we may insert the value into the index `key_index`.
+        // that we may insert the value into the index `key_index`. If the thread that inserted the
+        // key hasn't stored its hash yet, then store it now, so `put_if_match_in_newer_map()` and
+        // `copy_slot()` don't need to hash the key again.
+        self.store_hash(key_index, hash);
 
         let atomic_value_slot = &self.map[key_index].1;
         let mut old_value_slot: MaybeNull<_> = atomic_value_slot.load(&guard);
@@ -1127,31 +1666,53 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
             }
             // Early return if the expected value in `matcher` doesn't equal the current value.
             match matcher {
+                // An `EmptySeeNewTable` doesn't return early, because this map could have been
+                // copied into a newer map after the key slot was taken but before the value was
+                // put. Any other `SeeNewTable` had a value or tombstone, so it isn't empty even if
+                // the key isn't in the newer map anymore.
                 Match::Empty => if let Some(v) = value_slot_option {
-                    return Some(v.deref())
+                    if let &ValueSlot::EmptySeeNewTable = v.deref() {} else {
+                        return Some(v.deref())
+                    }
+                },
+                // A V' doesn't return early here, because it might have been removed in the newer
+                // map.
+                Match::NoValue => if let Some(v) = value_slot_option {
+                    if v.is_value() && !v.is_valueprime() {
+                        return Some(v.deref())
+                    }
                 },
                 Match::AnyKeyValuePair => match value_slot_option.map(|v| v.deref()) {
                     Some(&ValueSlot::Tombstone) | None => return None,
                     _ => (),
                 }
+                // As with `NoValue`, a V' must be compared in the newer map instead.
+                Match::Value(expected) => match value_slot_option {
+                    Some(v) if v.is_prime() => (),
+                    Some(v) => match v.deref() {
+                        &ValueSlot::Value(ref v) if v == expected => (),
+                        current => return Some(current),
+                    },
+                    None => return None,
+                }
                 Match::Always => (),
             }
-            // If it's prime then we need to copy the slot and try again in the new map.
+            // If it's prime then we need to copy the slot and try again in the new map. The copy
+            // must be counted, otherwise the newer map is never promoted.
             if value_slot_option.map_or(false, |v| v.is_prime()) {
-                let newer_map = self.newer_map
-                    .load(&guard)
-                    .as_option()
-                    .expect("Can't set a `ValueSlot` to `ValuePrime` before setting `newer_map`");
-                self.copy_slot(&*newer_map, key_index, outer_map, guard);
-                return newer_map.deref().put_if_match(key, put, matcher, outer_map, guard);
+                self.ensure_slot_copied(key_index, outer_map, guard);
+                return self.put_if_match_in_newer_map(
+                    key_index, key, put, matcher, outer_map, guard
+                );
             }
             // If the new map exists, help copy the current slot and some others and try again.
             if self.newer_map.relaxed_exists(guard) {
                 // TODO: if newer_map == None AND ((current_value is None AND table full) OR value
                 // is prime) then resize
-                return self.ensure_slot_copied(key_index, outer_map, guard)
-                    .deref()
-                    .put_if_match(key, put, matcher, outer_map, guard);
+                self.ensure_slot_copied(key_index, outer_map, guard);
+                return self.put_if_match_in_newer_map(
+                    key_index, key, put, matcher, outer_map, guard
+                );
             }
             debug_assert!(value_slot_option.map_or(true, |v| !v.is_prime()));
             // Otherwise, try to CAS the value.
@@ -1160,7 +1721,13 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                     old_value_slot, owned, &guard
                 ) {
                     Ok(_) => {
-                        return self.update_size_and_defer(old_value_slot, insert_tombstone, guard);
+                        let previous = self.update_size_and_defer(
+                            old_value_slot, insert_tombstone, guard
+                        );
+                        if insert_tombstone {
+                            self.maybe_auto_shrink(outer_map, guard);
+                        }
+                        return previous;
                     },
                     Err((current, _return_ownership)) => {
                         debug_assert!(current.as_option().is_some());
@@ -1172,7 +1739,13 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                     old_value_slot, shared, &guard
                 ) {
                     Ok(_) => {
-                        return self.update_size_and_defer(old_value_slot, insert_tombstone, guard);
+                        let previous = self.update_size_and_defer(
+                            old_value_slot, insert_tombstone, guard
+                        );
+                        if insert_tombstone {
+                            self.maybe_auto_shrink(outer_map, guard);
+                        }
+                        return previous;
                     },
                     Err((current, _return_ownership)) => {
                         debug_assert!(current.as_option().is_some());
@@ -1180,29 +1753,117 @@ impl<'guard, 'v: 'guard, K, V, S> MapInner<'v, K,V,S>
                         put = PutValue::Shared(cheat_lifetime(_return_ownership));
                     },
                 },
+                PutValue::Tombstone => unreachable!("Replaced by a shared sentinel above"),
             }
         }
     }
 
-    pub fn clone_hasher(&self) -> S {
-        self.hash_builder.clone()
+    /// Retries `put_if_match()` in `self.newer_map`, after the key slot at `key_index` was copied.
+    ///
+    /// If `key` is the key that was published into the key slot at `key_index`, then the same key
+    /// can be published into the newer map too. In that case, the key slot here is tagged so that
+    /// this map doesn't free the key when it's dropped, just like in `copy_slot()`.
+    fn put_if_match_in_newer_map<Q>(
+        &'guard self,
+        key_index: usize,
+        key: KeyCompare<'guard, '_, K, Q, A>,
+        put: PutValue<'v, V>,
+        matcher: Match<V>,
+        outer_map: &AtomicBox<Self>,
+        guard: &'guard Guard
+    ) -> Option<&'guard ValueSlot<V>>
+        where Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let newer_map = self.newer_map.load(guard)
+            .as_option()
+            .expect("A key slot can't be copied before setting `newer_map`");
+        // The slot was copied, so its value is now either `EmptySeeNewTable` or `SeeNewTable`. Only
+        // the former means that the key never had a value here. The value that the caller loaded
+        // could be out of date, e.g. null when a tombstone was put right after, and a tombstone
+        // isn't copied into the newer map. So a copy must not continue in the newer map then, or
+        // it would bring back a removed key.
+        if let Match::Empty = matcher {
+            let value_slot = self.map[key_index].1.load(guard)
+                .as_option()
+                .expect("A copied value slot can't be null");
+            if let &ValueSlot::EmptySeeNewTable = value_slot.deref() {} else {
+                return Some(value_slot.deref());
+            }
+        }
+        let atomic_key_slot = &self.map[key_index].0;
+        // `put_if_match()` already stored the hash of the key, unless the key slots don't store
+        // hashes.
+        let hash = match atomic_key_slot.stored_hash() {
+            0 => match key.as_key() {
+                Ok(k) => self.hash_key(k),
+                Err(q) => self.hash_key(q),
+            },
+            hash => hash,
+        };
+        let shared_key = match key {
+            KeyCompare::Shared(shared_key) if atomic_key_slot.owns(&shared_key, guard) =>
+                shared_key,
+            _ => return newer_map.deref()
+                .put_if_match_with_hash(hash, key, put, matcher, outer_map, guard),
+        };
+        let published = Cell::new(false);
+        let key = KeyCompare::<K, K, A>::Reclaimable(shared_key, &published);
+        let result = newer_map.deref().put_if_match_with_hash(
+            hash, key, put, matcher, outer_map, guard
+        );
+        if published.get() {
+            atomic_key_slot.tag(guard);
+        }
+        result
     }
 }
 
-impl<'v, K, V, S> Drop for MapInner<'v, K, V, S> {
-    fn drop(&mut self) {
-        let guard = &::pin();
-        for (mut k_ptr, mut v_ptr) in self.map.drain(..) {
-            unsafe {
-                guard.defer(move || {
-                    if !k_ptr.is_tagged(&guard) {
-                        k_ptr.try_drop(&guard);
-                    }
-                    v_ptr.try_drop(&guard);
-                })
-            }
+/// An iterator over the key/value pairs of the newest map in an `AtomicBox`, which the iterators
+/// of every map type are built on. Like `get()`, a pair that was copied into a newer map while
+/// iterating is looked up there.
+#[derive(Debug)]
+pub struct PairIter<'guard, 'v: 'guard, K: 'guard, V: 'v, S: 'guard, A: 'guard + AtomicKey<K>> {
+    position: usize,
+    guard: &'guard Guard,
+    map: &'guard MapInner<'v, K, V, S, A>,
+    outer_map: &'guard AtomicBox<MapInner<'v, K, V, S, A>>,
+}
+
+impl<'guard, 'v, K, V, S, A> PairIter<'guard, 'v, K, V, S, A>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+          A: AtomicKey<K>,
+{
+    /// Creates an iterator over the map in `outer_map`, after helping to finish copying it into
+    /// any newer maps.
+    pub fn new(outer_map: &'guard AtomicBox<MapInner<'v, K, V, S, A>>, guard: &'guard Guard)
+        -> Self
+    {
+        PairIter {
+            position: 0,
+            guard,
+            map: MapInner::load_newest(outer_map, guard),
+            outer_map,
         }
-        // Don't drop the `newer_map` ptr, because `self` could have been dropped from `promote()`.
     }
 }
 
+impl<'guard, 'v, K, V, S, A> Iterator for PairIter<'guard, 'v, K, V, S, A>
+    where K: Hash + Eq,
+          V: PartialEq,
+          S: BuildHasher + Clone,
+          A: AtomicKey<K>,
+{
+    type Item = (A::Ref<'guard>, &'guard V);
+    fn next(&mut self) -> Option<(A::Ref<'guard>, &'guard V)> {
+        while self.position < self.map.capacity() {
+            let pair = self.map.get_key_value_at(self.position, self.outer_map, self.guard);
+            self.position += 1;
+            if pair.is_some() {
+                return pair;
+            }
+        }
+        None
+    }
+}
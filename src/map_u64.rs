@@ -0,0 +1,513 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements [LockFreeHashMapU64], a map with `u64` keys that are stored in the
+//! table itself, like the `NonBlockingHashMapLong` in Dr. Click's library.
+//!
+//! The table is a [MapInner](::map_inner::MapInner) like any other map, but its key slots are
+//! [InlineKey]s instead of pointers to a `KeySlot`. Two keys are reserved for the states of a key
+//! slot: `EMPTY` for the null key slot, and `SEE_NEW_TABLE` for `KeySlot::SeeNewTable`. So
+//! inserting a key never allocates it, and copying a key into a newer map just copies the integer.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use atomic::{AtomicBox, NotNull, LOAD_ORDERING, RMW_ORDERING};
+use builder::Config;
+use counter::StripedCounter;
+use crossbeam_epoch::Guard;
+use key_slot::{AtomicKey, LoadedKey, PutOwned};
+use map_inner::{KeyCompare, KeySlot, MapInner, Match, PairIter, PutValue, ValueSlot};
+use {pin, LockFreeHashMap};
+
+/// The key of an empty key slot.
+const EMPTY: u64 = u64::MAX;
+/// The key of a key slot that was empty when the map was copied into a newer map.
+const SEE_NEW_TABLE: u64 = u64::MAX - 1;
+
+/// A key slot that stores a `u64` key in the slot itself.
+///
+/// Since a key isn't shared between an older and a newer map, the key slot can't be tagged to
+/// record that the newer map took ownership of a copied value. It has a flag for that instead.
+/// Hashes aren't stored either, because hashing a `u64` again is cheap and comparing two keys is
+/// as cheap as comparing their hashes.
+#[derive(Debug)]
+pub struct InlineKey {
+    key: AtomicU64,
+    copied: AtomicBool,
+}
+
+impl InlineKey {
+    /// Converts a key that was loaded from a key slot to a `LoadedKey`.
+    fn loaded(key: u64) -> LoadedKey<u64> {
+        match key {
+            EMPTY => LoadedKey::Empty,
+            SEE_NEW_TABLE => LoadedKey::SeeNewTable,
+            key => LoadedKey::Key(key),
+        }
+    }
+}
+
+impl AtomicKey<u64> for InlineKey {
+    type Owned = u64;
+    type Shared<'g> = u64;
+    type Ref<'g> = u64;
+
+    fn empty() -> Self {
+        InlineKey { key: AtomicU64::new(EMPTY), copied: AtomicBool::new(false) }
+    }
+
+    fn new_key(key: u64) -> u64 {
+        key
+    }
+
+    fn owned_key(key: &u64) -> &u64 {
+        key
+    }
+
+    fn shared_key<'a, 'g>(key: &'a Self::Shared<'g>) -> &'a u64 {
+        key
+    }
+
+    fn key_ref<'g>(key: Self::Shared<'g>) -> Self::Ref<'g> {
+        key
+    }
+
+    fn load<'g>(&self, _guard: &'g Guard) -> LoadedKey<Self::Shared<'g>> {
+        Self::loaded(self.key.load(LOAD_ORDERING))
+    }
+
+    fn put_owned<'g>(&self, key: u64, guard: &'g Guard) -> PutOwned<'g, u64, Self> {
+        self.put_shared(key, guard).map_err(|current| (current, key))
+    }
+
+    fn put_shared<'g>(&self, key: Self::Shared<'g>, _guard: &'g Guard)
+        -> Result<Self::Shared<'g>, LoadedKey<Self::Shared<'g>>>
+    {
+        self.key.compare_exchange(EMPTY, key, RMW_ORDERING, LOAD_ORDERING)
+            .map(|_| key)
+            .map_err(Self::loaded)
+    }
+
+    fn put_see_new_table<'g>(&self, _sentinel: NotNull<'g, KeySlot<u64>>, guard: &'g Guard)
+        -> Result<(), LoadedKey<u64>>
+    {
+        self.put_shared(SEE_NEW_TABLE, guard).map(|_| ())
+    }
+
+    fn owns<'g>(&self, _key: &Self::Shared<'g>, _guard: &'g Guard) -> bool {
+        // Keys are copied by value, so no key slot owns a key that a newer map could take over.
+        false
+    }
+
+    fn tag(&self, _guard: &Guard) {
+        self.copied.store(true, Ordering::Release);
+    }
+
+    fn is_tagged(&self, _guard: &Guard) -> bool {
+        self.copied.load(Ordering::Acquire)
+    }
+
+    fn stored_hash(&self) -> u64 {
+        0
+    }
+
+    fn store_hash(&self, _hash: u64) {}
+
+    unsafe fn take(&self, _guard: &Guard) -> Option<u64> {
+        match self.key.swap(EMPTY, RMW_ORDERING) {
+            EMPTY => None,
+            SEE_NEW_TABLE => unreachable!("A map without a newer map has no `SEE_NEW_TABLE`"),
+            key => Some(key),
+        }
+    }
+
+    unsafe fn drop_key(&mut self, _sentinel: &KeySlot<u64>) {}
+}
+
+/// A concurrent, lock-free hash map with `u64` keys, which are stored in the table itself instead
+/// of being allocated.
+///
+/// The two largest keys are reserved for the states of a key slot, so keys can be at most
+/// [MAX_KEY](LockFreeHashMapU64::MAX_KEY). Otherwise, this works like a
+/// `LockFreeHashMap<u64, V>`, including how it's resized, and it can be configured with
+/// `LockFreeHashMapBuilder::build_u64()`.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// let map = LockFreeHashMapU64::<String>::new();
+/// let guard = lockfreehashmap::pin();
+/// map.insert(1, "one".to_string(), &guard);
+/// assert_eq!(map.get(1, &guard), Some(&"one".to_string()));
+/// assert_eq!(map.remove(1, &guard), Some(&"one".to_string()));
+/// assert_eq!(map.get(1, &guard), None);
+/// ```
+pub struct LockFreeHashMapU64<'v, V: 'v, S = RandomState> {
+    /// Points to the newest map (after it's been fully resized). Always non-null.
+    inner: AtomicBox<MapInner<'v, u64, V, S, InlineKey>>,
+}
+
+impl<'guard, 'v: 'guard, V, S> LockFreeHashMapU64<'v, V, S>
+    where V: PartialEq,
+          S: 'guard + BuildHasher + Clone,
+{
+    /// The default size of a new `LockFreeHashMapU64` when created by `LockFreeHashMapU64::new()`.
+    pub const DEFAULT_CAPACITY: usize = LockFreeHashMap::<(), ()>::DEFAULT_CAPACITY;
+
+    /// The largest key that can be inserted. The keys above it are reserved.
+    pub const MAX_KEY: u64 = SEE_NEW_TABLE - 1;
+
+    /// Creates an empty `LockFreeHashMapU64` with the specified capacity, using `hasher` to hash
+    /// the keys. Uses the next power of two if `capacity` is not a power of two.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let map = LockFreeHashMapU64::with_capacity_and_hasher(10, RandomState::new());
+    /// map.insert(1, 2, &lockfreehashmap::pin());
+    /// assert_eq!(map.capacity(), 16);
+    /// ```
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self::with_config(capacity, hasher, Config::default())
+    }
+
+    /// Creates an empty map with the given settings. See `LockFreeHashMapBuilder::build_u64()`.
+    pub(crate) fn with_config(capacity: usize, hasher: S, config: Config) -> Self {
+        LockFreeHashMapU64 {
+            inner: AtomicBox::new(MapInner::with_config(capacity, hasher, config)),
+        }
+    }
+
+    /// Private helper method to load the `inner` field as a &[MapInner].
+    fn load_inner(&self, guard: &'guard Guard) -> &'guard MapInner<'v, u64, V, S, InlineKey> {
+        self.inner.load(guard).deref()
+    }
+
+
+    /// Returns the number of elements the map can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        let guard = pin();
+        self.load_inner(&guard).capacity()
+    }
+
+    /// Returns the number of elements in the map. See `LockFreeHashMap::len()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..100 {
+    ///     map.insert(i, i as u32, &guard);
+    /// }
+    /// assert_eq!(map.len(), 100);
+    /// ```
+    pub fn len(&self) -> usize {
+        let guard = pin();
+        MapInner::sum_over_newer_maps(&self.inner, StripedCounter::sum, &guard)
+    }
+
+    /// Returns an estimate of the number of elements in the map, which is faster than `len()`.
+    /// See `LockFreeHashMap::len_estimate()`.
+    pub fn len_estimate(&self) -> usize {
+        let guard = pin();
+        MapInner::sum_over_newer_maps(&self.inner, StripedCounter::estimate, &guard)
+    }
+
+    /// Returns true if the map contains no elements.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// assert!(map.is_empty());
+    /// map.insert(1, 2, &lockfreehashmap::pin());
+    /// assert!(!map.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Clears the entire map. See `LockFreeHashMap::clear()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// map.insert(1, 2, &lockfreehashmap::pin());
+    /// map.clear();
+    /// assert_eq!(map.len(), 0);
+    /// ```
+    pub fn clear(&self) {
+        let guard = pin();
+        let newer_map = self.load_inner(&guard).with_capacity_from(Self::DEFAULT_CAPACITY);
+        self.inner.replace(newer_map);
+    }
+
+    /// Reserves capacity for at least `additional` more key/value pairs. See
+    /// `LockFreeHashMap::reserve()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// map.reserve(1000);
+    /// assert_eq!(map.capacity(), 2048);
+    /// ```
+    pub fn reserve(&self, additional: usize) {
+        let guard = pin();
+        while !MapInner::load_newest(&self.inner, &guard).reserve(additional, &self.inner, &guard) {}
+        MapInner::load_newest(&self.inner, &guard);
+    }
+
+    /// Shrinks the capacity of the map as much as possible, while keeping the map at most half
+    /// full. See `LockFreeHashMap::shrink_to_fit()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::with_capacity(1024);
+    /// let guard = lockfreehashmap::pin();
+    /// for i in 0..10 {
+    ///     map.insert(i, i as u32, &guard);
+    /// }
+    /// map.shrink_to_fit();
+    /// assert_eq!(map.capacity(), 32);
+    /// assert!((0..10).all(|i| map.get(i, &guard) == Some(&(i as u32))));
+    /// ```
+    pub fn shrink_to_fit(&self) {
+        let guard = pin();
+        MapInner::load_newest(&self.inner, &guard).shrink_to(0, &self.inner, &guard);
+    }
+
+    /// Returns true if the map contains a value for `key`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// map.insert(3, 8934, &lockfreehashmap::pin());
+    /// assert!(map.contains_key(3));
+    /// assert!(!map.contains_key(4));
+    /// ```
+    pub fn contains_key(&self, key: u64) -> bool {
+        let guard = pin();
+        self.get(key, &guard).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to `key`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.get(1, &guard), None);
+    /// map.insert(1, 15, &guard);
+    /// assert_eq!(map.get(1, &guard), Some(&15));
+    /// ```
+    pub fn get<'s: 'guard>(&'s self, key: u64, guard: &'guard Guard) -> Option<&'guard V> {
+        if key > Self::MAX_KEY {
+            return None;
+        }
+        self.load_inner(guard).get(&key, &self.inner, guard)
+    }
+
+    /// Inserts a key-value pair into the map. If the map did not have this key present, None is
+    /// returned. Otherwise, the value is updated, and the old value is returned.
+    ///
+    /// # Panics
+    /// Panics if `key` is greater than [MAX_KEY](LockFreeHashMapU64::MAX_KEY).
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(map.insert(1, 2, &guard), None);
+    /// assert_eq!(map.insert(1, 3, &guard), Some(&2));
+    /// ```
+    pub fn insert<'s: 'guard>(&'s self, key: u64, value: V, guard: &'guard Guard)
+        -> Option<&'guard V>
+    {
+        assert!(key <= Self::MAX_KEY, "key {} is reserved", key);
+        let value_slot = self.load_inner(guard).put_if_match(
+            KeyCompare::new(key),
+            PutValue::new(value),
+            Match::Always,
+            &self.inner,
+            guard
+        );
+        ValueSlot::as_inner(value_slot)
+    }
+
+    /// Removes `key` from the map, returning the value at the key if the key was previously in the
+    /// map.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(1, 2, &guard);
+    /// assert_eq!(map.remove(1, &guard), Some(&2));
+    /// assert_eq!(map.remove(1, &guard), None);
+    /// ```
+    pub fn remove<'s: 'guard>(&'s self, key: u64, guard: &'guard Guard) -> Option<&'guard V> {
+        if key > Self::MAX_KEY {
+            return None;
+        }
+        let value_slot = self.load_inner(guard).put_if_match(
+            KeyCompare::OnlyCompare(&key),
+            PutValue::new_tombstone(),
+            Match::Always,
+            &self.inner,
+            guard
+        );
+        ValueSlot::as_inner(value_slot)
+    }
+
+    /// An iterator visiting all key/value pairs in arbitrary order, with the same guarantees as
+    /// `LockFreeHashMap::iter()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<&str>::new();
+    /// let guard = lockfreehashmap::pin();
+    /// map.insert(4, "Four", &guard);
+    /// map.insert(8, "Eight", &guard);
+    ///
+    /// let mut pairs = map.iter(&guard).collect::<Vec<_>>();
+    /// pairs.sort();
+    /// assert_eq!(pairs, vec![(4, &"Four"), (8, &"Eight")]);
+    /// ```
+    pub fn iter<'s: 'guard>(&'s self, guard: &'guard Guard) -> IterU64<'guard, 'v, V, S> {
+        IterU64 { pairs: PairIter::new(&self.inner, guard) }
+    }
+}
+
+impl<'v, V: PartialEq> LockFreeHashMapU64<'v, V> {
+    /// Creates a new `LockFreeHashMapU64`.
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    /// Creates a new `LockFreeHashMapU64` of a given size. Uses the next power of two if size is
+    /// not a power of two.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<u32>::with_capacity(12);
+    /// assert_eq!(map.capacity(), 16);
+    /// ```
+    pub fn with_capacity(size: usize) -> Self {
+        Self::with_capacity_and_hasher(size, RandomState::new())
+    }
+}
+
+impl<'v, V, S> Drop for LockFreeHashMapU64<'v, V, S> {
+    fn drop(&mut self) {
+        MapInner::drop_newer_maps_in(&mut self.inner);
+    }
+}
+
+impl<'v, V, S> Default for LockFreeHashMapU64<'v, V, S>
+    where V: PartialEq,
+          S: BuildHasher + Clone + Default,
+{
+    /// Creates an empty `LockFreeHashMapU64` with the default capacity, using the default value of
+    /// the hasher.
+    fn default() -> Self {
+        Self::with_capacity_and_hasher(Self::DEFAULT_CAPACITY, S::default())
+    }
+}
+
+impl<'v, V, S> fmt::Debug for LockFreeHashMapU64<'v, V, S>
+    where V: PartialEq + fmt::Debug,
+          S: BuildHasher + Clone,
+{
+    /// Formats the key/value pairs of the map like a [HashMap](::std::collections::HashMap).
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let map = LockFreeHashMapU64::<&str>::new();
+    /// map.insert(1, "one", &lockfreehashmap::pin());
+    /// assert_eq!(format!("{:?}", map), r#"{1: "one"}"#);
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let guard = pin();
+        f.debug_map().entries(self.iter(&guard)).finish()
+    }
+}
+
+/// An iterator over the key/value pairs of a [LockFreeHashMapU64]. This is created by
+/// `LockFreeHashMapU64::iter()`.
+#[derive(Debug)]
+pub struct IterU64<'guard, 'v: 'guard, V: 'v, S: 'guard> {
+    pairs: PairIter<'guard, 'v, u64, V, S, InlineKey>,
+}
+
+impl<'guard, 'v, V, S> Iterator for IterU64<'guard, 'v, V, S>
+    where V: PartialEq,
+          S: BuildHasher + Clone,
+{
+    type Item = (u64, &'guard V);
+    fn next(&mut self) -> Option<(u64, &'guard V)> {
+        self.pairs.next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test::run_threads;
+
+    #[test]
+    fn test_map_u64_during_resizes() {
+        let map = &LockFreeHashMapU64::<u64>::with_capacity(1);
+        run_threads(4, 256, |keys, guard| {
+            for i in keys.map(u64::from) {
+                assert_eq!(map.insert(i, i, guard), None);
+                assert_eq!(map.insert(i, i + 1, guard), Some(&i));
+                if i % 2 == 0 {
+                    assert_eq!(map.remove(i, guard), Some(&(i + 1)));
+                }
+            }
+        });
+        let guard = pin();
+        assert_eq!(map.len(), 512);
+        for i in 0..1024 {
+            let expected = if i % 2 == 0 { None } else { Some(i + 1) };
+            assert_eq!(map.get(i, &guard), expected.as_ref());
+        }
+        assert_eq!(map.iter(&guard).count(), 512);
+        // The reserved keys are never found.
+        assert!(!map.contains_key(u64::MAX));
+        assert_eq!(map.remove(u64::MAX - 1, &guard), None);
+        assert_eq!(map.insert(LockFreeHashMapU64::<u64>::MAX_KEY, 0, &guard), None);
+        assert!(map.contains_key(LockFreeHashMapU64::<u64>::MAX_KEY));
+        map.clear();
+        assert_eq!(map.len(), 0);
+    }
+}
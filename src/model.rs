@@ -0,0 +1,335 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `loom` models of the map's copy protocol, with the memory orderings in [atomic](::atomic).
+//!
+//! `loom` runs every test under every interleaving of its threads, and every outcome of their
+//! atomic operations that the C++11 memory model allows for the given orderings. It can't run the
+//! map itself, because `crossbeam_epoch` uses the standard library's atomics, which `loom` can't
+//! intercept. Instead, each test is a hand-written model of one of the state transitions in
+//! `map_inner`, built on `loom`'s atomics with the same ordering constants as the map.
+//!
+//! So these tests check the protocol, not its implementation. They only show that the state
+//! transitions are correct with these orderings, not that `map_inner` performs them as modelled,
+//! and a change to a transition in `map_inner` has to be made to its model here by hand.
+//!
+//! The counters that are only updated with `Relaxed`, e.g. `key_slots_used` and `chunks_copied`,
+//! aren't modelled, because they don't publish any other data. The hashes that are stored next to
+//! the keys are, because `copy_slot()` relies on them.
+//!
+//! Run them with:
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib model
+//! ```
+
+extern crate loom;
+
+use self::loom::cell::UnsafeCell;
+use self::loom::sync::Arc;
+use self::loom::sync::atomic::{AtomicPtr, AtomicUsize};
+use self::loom::thread;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use atomic::{LOAD_ORDERING, RMW_ORDERING};
+
+/// A key or value. `loom` checks that every thread that reads it sees the write that initialized
+/// it, i.e. that the thread that published it happens before the thread that reads it.
+struct Payload(UnsafeCell<usize>);
+
+impl Payload {
+    fn new(value: usize) -> *mut Payload {
+        let payload = Box::new(Payload(UnsafeCell::new(0)));
+        payload.0.with_mut(|inner| unsafe { *inner = value });
+        Box::into_raw(payload)
+    }
+}
+
+/// Reads a payload, ignoring the tag of a V'.
+fn read(payload: *mut Payload) -> usize {
+    let payload = (payload as usize & !1) as *mut Payload;
+    unsafe { (*payload).0.with(|inner| *inner) }
+}
+
+fn is_tagged(payload: *mut Payload) -> bool {
+    payload as usize & 1 == 1
+}
+
+fn with_tag(payload: *mut Payload) -> *mut Payload {
+    (payload as usize | 1) as *mut Payload
+}
+
+fn without_tag(payload: *mut Payload) -> *mut Payload {
+    (payload as usize & !1) as *mut Payload
+}
+
+/// (K, ∅) -> (K, V) in one thread, while another thread reads the value and then the key.
+#[test]
+fn test_publish_key_and_value() {
+    loom::model(|| {
+        let slot = Arc::new((AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())));
+        let writer = {
+            let slot = slot.clone();
+            thread::spawn(move || {
+                let (ref key, ref value) = *slot;
+                key.compare_exchange(ptr::null_mut(), Payload::new(1), RMW_ORDERING, LOAD_ORDERING)
+                    .unwrap();
+                value.compare_exchange(
+                    ptr::null_mut(), Payload::new(2), RMW_ORDERING, LOAD_ORDERING
+                ).unwrap();
+            })
+        };
+        let (ref key, ref value) = *slot;
+        let loaded_value = value.load(LOAD_ORDERING);
+        if !loaded_value.is_null() {
+            let loaded_key = key.load(LOAD_ORDERING);
+            assert!(!loaded_key.is_null());
+            assert_eq!(read(loaded_key), 1);
+            assert_eq!(read(loaded_value), 2);
+        }
+        writer.join().unwrap();
+        unsafe {
+            drop(Box::from_raw(key.load(LOAD_ORDERING)));
+            drop(Box::from_raw(value.load(LOAD_ORDERING)));
+        }
+    });
+}
+
+/// A value slot in an older map and the same key's value slot in the newer map.
+struct Copy {
+    old: AtomicPtr<Payload>,
+    new: AtomicPtr<Payload>,
+    /// The `SeeNewTable` sentinel (X).
+    see_new_table: *mut Payload,
+}
+
+unsafe impl Send for Copy {}
+unsafe impl Sync for Copy {}
+
+impl Copy {
+    /// The transitions of `copy_slot()`: (K, V) -> (K, V'), copying V into the newer map if it
+    /// doesn't have a value yet, then (K, V') -> (K, X).
+    fn copy_slot(&self) {
+        let primed = loop {
+            let current = self.old.load(LOAD_ORDERING);
+            if current == self.see_new_table {
+                return;
+            } else if is_tagged(current) {
+                break current;
+            }
+            let primed = with_tag(current);
+            if self.old.compare_exchange(current, primed, RMW_ORDERING, LOAD_ORDERING).is_ok() {
+                break primed;
+            }
+        };
+        let _ = self.put_in_newer_map(without_tag(primed), |current| current.is_null());
+        let _ = self.old.compare_exchange(
+            primed, self.see_new_table, RMW_ORDERING, LOAD_ORDERING
+        );
+    }
+
+    /// `put_if_match()` in the newer map, which replaces the current value if `matches` returns
+    /// true for it. `copy_slot()` uses `Match::Empty`, which only matches an empty slot. Returns
+    /// the value that was replaced, or else the current value.
+    fn put_in_newer_map<F>(&self, value: *mut Payload, matches: F)
+        -> Result<*mut Payload, *mut Payload>
+        where F: Fn(*mut Payload) -> bool
+    {
+        let mut current = self.new.load(LOAD_ORDERING);
+        loop {
+            if !matches(current) {
+                return Err(current);
+            }
+            match self.new.compare_exchange(current, value, RMW_ORDERING, LOAD_ORDERING) {
+                Ok(previous) => return Ok(previous),
+                Err(newer) => current = newer,
+            }
+        }
+    }
+
+    /// `put_if_match()` with `Match::Always`, which writes into the older map unless the slot is
+    /// being copied, in which case it helps copy it and writes into the newer map instead.
+    fn insert(&self, value: *mut Payload) {
+        loop {
+            let current = self.old.load(LOAD_ORDERING);
+            if current == self.see_new_table || is_tagged(current) {
+                self.copy_slot();
+                let _ = self.put_in_newer_map(value, |_| true);
+                return;
+            }
+            if self.old.compare_exchange(current, value, RMW_ORDERING, LOAD_ORDERING).is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// `get()`, which helps copy the slot if it's being copied and then reads the newer map.
+    fn get(&self) -> usize {
+        let current = self.old.load(LOAD_ORDERING);
+        if current == self.see_new_table || is_tagged(current) {
+            self.copy_slot();
+            read(self.new.load(LOAD_ORDERING))
+        } else {
+            read(current)
+        }
+    }
+}
+
+/// An insert that races with the copy of the same slot is never lost, and a concurrent reader
+/// sees either the old or the new value.
+#[test]
+fn test_insert_during_copy() {
+    loom::model(|| {
+        let copy = Arc::new(Copy {
+            old: AtomicPtr::new(Payload::new(1)),
+            new: AtomicPtr::new(ptr::null_mut()),
+            see_new_table: Payload::new(0),
+        });
+        let old_value = copy.old.load(LOAD_ORDERING);
+        let copier = {
+            let copy = copy.clone();
+            thread::spawn(move || copy.copy_slot())
+        };
+        let writer = {
+            let copy = copy.clone();
+            thread::spawn(move || copy.insert(Payload::new(2)))
+        };
+        let value = copy.get();
+        assert!(value == 1 || value == 2);
+        copier.join().unwrap();
+        writer.join().unwrap();
+        // The insert finished after the slot was copied or before it was marked as being copied,
+        // so the newer map has its value either way.
+        assert_eq!(copy.old.load(LOAD_ORDERING), copy.see_new_table);
+        let new_value = copy.new.load(LOAD_ORDERING);
+        assert_ne!(new_value, old_value);
+        assert_eq!(copy.get(), 2);
+        unsafe {
+            drop(Box::from_raw(old_value));
+            drop(Box::from_raw(new_value));
+            drop(Box::from_raw(copy.see_new_table));
+        }
+    });
+}
+
+/// A key slot, the hash that is stored next to it and its value slot.
+struct HashedSlot {
+    key: AtomicPtr<Payload>,
+    hash: AtomicUsize,
+    value: AtomicPtr<Payload>,
+}
+
+/// A hash function that never returns 0, which means that the hash isn't stored yet.
+fn hash_of(key: usize) -> usize {
+    key * 31 + 1
+}
+
+/// `put_if_match()` publishes the key, stores its hash with `Relaxed` and then puts the value,
+/// while two threads copy the slot. They load the hash with `Relaxed` after loading V or V', and
+/// always see it, because V' is put by an RMW that continues the release sequence of V.
+#[test]
+fn test_copy_reads_stored_hash() {
+    loom::model(|| {
+        let slot = Arc::new(HashedSlot {
+            key: AtomicPtr::new(ptr::null_mut()),
+            hash: AtomicUsize::new(0),
+            value: AtomicPtr::new(ptr::null_mut()),
+        });
+        let writer = {
+            let slot = slot.clone();
+            thread::spawn(move || {
+                slot.key.compare_exchange(
+                    ptr::null_mut(), Payload::new(5), RMW_ORDERING, LOAD_ORDERING
+                ).unwrap();
+                // `store_hash()`
+                if slot.hash.load(Ordering::Relaxed) == 0 {
+                    slot.hash.store(hash_of(5), Ordering::Relaxed);
+                }
+                slot.value.compare_exchange(
+                    ptr::null_mut(), Payload::new(6), RMW_ORDERING, LOAD_ORDERING
+                ).unwrap();
+            })
+        };
+        // The first transition of `copy_slot()`, (K, V) -> (K, V'), or helping the other copier
+        // after it did that. Without a value, the slot is copied without its hash.
+        let copy_slot = |slot: &HashedSlot| {
+            let primed = loop {
+                let current = slot.value.load(LOAD_ORDERING);
+                if current.is_null() {
+                    return;
+                } else if is_tagged(current) {
+                    break current;
+                }
+                let primed = with_tag(current);
+                if slot.value.compare_exchange(current, primed, RMW_ORDERING, LOAD_ORDERING)
+                    .is_ok()
+                {
+                    break primed;
+                }
+            };
+            let key = slot.key.load(LOAD_ORDERING);
+            assert_eq!(slot.hash.load(Ordering::Relaxed), hash_of(read(key)));
+            assert_eq!(read(primed), 6);
+        };
+        let copier = {
+            let slot = slot.clone();
+            thread::spawn(move || copy_slot(&slot))
+        };
+        copy_slot(&slot);
+        writer.join().unwrap();
+        copier.join().unwrap();
+        unsafe {
+            drop(Box::from_raw(slot.key.load(LOAD_ORDERING)));
+            drop(Box::from_raw(without_tag(slot.value.load(LOAD_ORDERING))));
+        }
+    });
+}
+
+/// Two threads copy a slot each and add to `slots_copied`, and the one that adds the last slot
+/// promotes the newer map. A thread that loads the promoted map sees both copies.
+#[test]
+fn test_promote_after_copies() {
+    loom::model(|| {
+        let new = Arc::new([AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())]);
+        let slots_copied = Arc::new(AtomicUsize::new(0));
+        // 0 for the older map and 1 for the newer map.
+        let outer = Arc::new(AtomicUsize::new(0));
+        let copiers = (0..2).map(|slot| {
+            let (new, slots_copied, outer) = (new.clone(), slots_copied.clone(), outer.clone());
+            thread::spawn(move || {
+                new[slot].compare_exchange(
+                    ptr::null_mut(), Payload::new(slot), RMW_ORDERING, LOAD_ORDERING
+                ).unwrap();
+                if slots_copied.fetch_add(1, RMW_ORDERING) + 1 == 2 {
+                    outer.compare_exchange(0, 1, RMW_ORDERING, LOAD_ORDERING).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        if outer.load(LOAD_ORDERING) == 1 {
+            for slot in 0..2 {
+                let value = new[slot].load(LOAD_ORDERING);
+                assert!(!value.is_null());
+                assert_eq!(read(value), slot);
+            }
+        }
+        for copier in copiers {
+            copier.join().unwrap();
+        }
+        for slot in 0..2 {
+            unsafe { drop(Box::from_raw(new[slot].load(LOAD_ORDERING))); }
+        }
+    });
+}
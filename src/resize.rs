@@ -0,0 +1,143 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the policies that decide how a map is resized.
+//!
+//! A map is resized when an insert can't find a free key slot within the reprobe limit. Because
+//! key slots are never freed, this happens both when the map is full of live entries and when
+//! it's full of keys that have since been removed (i.e. keys with a tombstone value). Copying
+//! the live entries into a newer map of the same size is enough to free up the latter.
+
+use std::thread;
+use std::time::Duration;
+
+/// The state of a map at the time it has to be resized. The values are only estimates, since
+/// other threads can change the map at the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResizeStats {
+    /// The number of key slots in the map being resized.
+    pub capacity: usize,
+    /// The number of live key/value pairs in the map.
+    pub len: usize,
+    /// The number of key slots that have been claimed by a key, including keys that have since
+    /// been removed.
+    pub key_slots_used: usize,
+}
+
+impl ResizeStats {
+    /// Returns the number of claimed key slots that don't have a live value.
+    pub fn dead_key_slots(&self) -> usize {
+        self.key_slots_used.saturating_sub(self.len)
+    }
+}
+
+/// Decides the capacity of the newer map when a map has to be resized.
+///
+/// The returned capacity is rounded up to the next power of two. It's also never less than
+/// `stats.len + 1`, so that the live entries always fit into the newer map.
+pub trait ResizePolicy: Send + Sync {
+    /// Returns the capacity of the newer map.
+    fn new_capacity(&self, stats: &ResizeStats) -> usize;
+}
+
+/// The default [ResizePolicy], which is the same as the one in Dr. Click's hash map.
+///
+/// The capacity is doubled if at least a quarter of the key slots have a live value, and
+/// quadrupled if at least half of them do. Otherwise, most of the key slots are used by removed
+/// keys, so the capacity stays the same and the copy just gets rid of them.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// let policy = DefaultResizePolicy;
+/// let stats = |len| ResizeStats { capacity: 64, len, key_slots_used: 64 };
+/// assert_eq!(policy.new_capacity(&stats(4)), 64);
+/// assert_eq!(policy.new_capacity(&stats(16)), 128);
+/// assert_eq!(policy.new_capacity(&stats(32)), 256);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultResizePolicy;
+
+impl ResizePolicy for DefaultResizePolicy {
+    fn new_capacity(&self, stats: &ResizeStats) -> usize {
+        let capacity = stats.capacity;
+        if stats.len >= capacity >> 1 {
+            capacity.saturating_mul(4)
+        } else if stats.len >= capacity >> 2 {
+            capacity.saturating_mul(2)
+        } else {
+            capacity
+        }
+    }
+}
+
+/// Decides what a thread does when it needs to resize a map that another thread is already
+/// resizing, but the other thread hasn't finished allocating the newer map yet.
+///
+/// Allocating a big map takes a while, so rather than every thread allocating its own newer map
+/// and all but one of them being dropped, threads can wait a bit for the first thread's map. The
+/// wait is proportional to the size of the newer map in megabytes, and stops as soon as the newer
+/// map is available. Maps smaller than a megabyte are always allocated without waiting.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// let map: LockFreeHashMap<u32, u32> = LockFreeHashMapBuilder::new()
+///     .resize_contention(ResizeContention::Yield(100))
+///     .build();
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeContention {
+    /// Never wait. Every thread that needs to resize allocates a newer map.
+    None,
+    /// Busy-wait for up to this many iterations per megabyte.
+    Spin(u32),
+    /// Call `std::thread::yield_now()` up to this many times per megabyte.
+    Yield(u32),
+    /// Sleep for up to this long per megabyte. This is the default, with one millisecond per
+    /// megabyte, which is the same as in Dr. Click's hash map.
+    Sleep(Duration),
+}
+
+impl Default for ResizeContention {
+    fn default() -> Self {
+        ResizeContention::Sleep(Duration::from_millis(1))
+    }
+}
+
+impl ResizeContention {
+    /// Waits according to this strategy for a newer map of size `megabytes`, until `resized()`
+    /// returns true.
+    pub(crate) fn wait<F: FnMut() -> bool>(&self, megabytes: usize, mut resized: F) {
+        let times = match *self {
+            ResizeContention::None => return,
+            ResizeContention::Spin(n) | ResizeContention::Yield(n) =>
+                megabytes.saturating_mul(n as usize),
+            ResizeContention::Sleep(_) => megabytes,
+        };
+        for _ in 0..times {
+            if resized() {
+                return;
+            }
+            match *self {
+                ResizeContention::None => unreachable!(),
+                ResizeContention::Spin(_) => ::std::hint::spin_loop(),
+                ResizeContention::Yield(_) => thread::yield_now(),
+                ResizeContention::Sleep(duration) => thread::sleep(duration),
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements [LockFreeHashSet], a set that uses the same table as [LockFreeHashMap].
+//!
+//! A set is a map from its elements to `()`. Every key of a map needs a value slot, since removing
+//! a key and copying it into a resized table are changes to its value. But instead of allocating
+//! a `ValueSlot::Value(())` for each element, every value slot of a set points to the same one,
+//! which is one of the map's sentinels. So an insert only allocates the element itself.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+
+use atomic::AtomicBox;
+use builder::Config;
+use equivalent::Equivalent;
+use map_inner::{KeyCompare, MapInner, Match, PutValue, ValueSlot};
+use {pin, Guard, Keys, LockFreeHashMap};
+
+/// A concurrent, lock-free hash set.
+///
+/// The elements are stored in the same kind of table as the keys of a [LockFreeHashMap], and it
+/// is resized in the same way.
+///
+/// # Examples
+/// ```
+/// # use lockfreehashmap::*;
+/// let set = LockFreeHashSet::<u32>::new();
+/// assert!(set.insert(1));
+/// assert!(!set.insert(1));
+/// assert!(set.contains(&1));
+/// assert!(set.remove(&1));
+/// assert!(!set.contains(&1));
+/// ```
+pub struct LockFreeHashSet<T, S = RandomState> {
+    map: LockFreeHashMap<'static, T, (), S>,
+}
+
+impl<'guard, T, S> LockFreeHashSet<T, S>
+    where T: 'guard + Hash + Eq,
+          S: 'guard + BuildHasher + Clone,
+{
+    /// Creates an empty `LockFreeHashSet` with the specified capacity, using `hasher` to hash the
+    /// elements. Like `LockFreeHashMap::with_capacity_and_hasher()`, this uses the next power of
+    /// two if `capacity` is not a power of two.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let set = LockFreeHashSet::with_capacity_and_hasher(10, RandomState::new());
+    /// set.insert("one");
+    /// assert_eq!(set.capacity(), 16);
+    /// ```
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self::with_config(capacity, hasher, Config::default())
+    }
+
+    /// Creates an empty set with the given settings. See `LockFreeHashMapBuilder::build_set()`.
+    pub(crate) fn with_config(capacity: usize, hasher: S, config: Config) -> Self {
+        let inner = MapInner::with_shared_value(capacity, hasher, config, ());
+        LockFreeHashSet {
+            map: LockFreeHashMap { inner: AtomicBox::new(inner) },
+        }
+    }
+
+    /// Returns the number of elements the set can hold without reallocating.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::with_capacity(8);
+    /// assert_eq!(set.capacity(), 8);
+    /// ```
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
+    /// Returns the number of elements in the set. See `LockFreeHashMap::len()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// set.insert(1);
+    /// set.insert(2);
+    /// set.insert(1);
+    /// assert_eq!(set.len(), 2);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the set contains no elements. See `LockFreeHashMap::len()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// assert!(set.is_empty());
+    /// set.insert(1);
+    /// assert!(!set.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an estimate of the number of elements in the set, which is faster than `len()`.
+    /// See `LockFreeHashMap::len_estimate()`.
+    pub fn len_estimate(&self) -> usize {
+        self.map.len_estimate()
+    }
+
+    /// Clears the entire set. See `LockFreeHashMap::clear()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// set.insert(1);
+    /// set.clear();
+    /// assert_eq!(set.len(), 0);
+    /// assert!(!set.contains(&1));
+    /// ```
+    pub fn clear(&self) {
+        self.map.clear();
+    }
+
+    /// Reserves capacity for at least `additional` more elements. See `LockFreeHashMap::reserve()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// set.reserve(1000);
+    /// assert_eq!(set.capacity(), 2048);
+    /// ```
+    pub fn reserve(&self, additional: usize) {
+        self.map.reserve(additional);
+    }
+
+    /// Shrinks the capacity of the set as much as possible, while keeping it at most half full.
+    /// See `LockFreeHashMap::shrink_to_fit()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::with_capacity(1024);
+    /// set.insert(1);
+    /// set.shrink_to_fit();
+    /// assert_eq!(set.capacity(), 2);
+    /// ```
+    pub fn shrink_to_fit(&self) {
+        self.map.shrink_to_fit();
+    }
+
+    /// Returns true if the set contains `value`.
+    ///
+    /// The value may be any type that is [Equivalent] to the set's element type, such as a borrowed
+    /// form of it, but its hash must match the hash of the element.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<String>::new();
+    /// set.insert("one".to_string());
+    /// assert!(set.contains("one"));
+    /// assert!(!set.contains("two"));
+    /// ```
+    pub fn contains<Q>(&self, value: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<T>,
+    {
+        self.map.contains_key(value)
+    }
+
+    /// Returns a reference to the element in the set that is equal to `value`, if any.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<String>::new();
+    /// set.insert("one".to_string());
+    /// let guard = lockfreehashmap::pin();
+    /// assert_eq!(set.get("one", &guard), Some(&"one".to_string()));
+    /// assert_eq!(set.get("two", &guard), None);
+    /// ```
+    pub fn get<'s: 'guard, Q>(&'s self, value: &Q, guard: &'guard Guard)
+        -> Option<&'guard T>
+        where Q: ?Sized + Hash + Equivalent<T>,
+    {
+        self.map.load_inner(guard)
+            .get_key_value(value, &self.map.inner, guard)
+            .map(|(element, _)| element)
+    }
+
+    /// Adds `value` to the set. Returns true if the set did not have an equal element present.
+    /// Otherwise, the element in the set is not replaced and `value` is dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// assert!(set.insert(1));
+    /// assert!(!set.insert(1));
+    /// ```
+    pub fn insert(&self, value: T) -> bool {
+        let guard = pin();
+        let inner = self.map.load_inner(&guard);
+        let value_slot: Option<&ValueSlot<()>> = inner.put_if_match(
+            KeyCompare::new(value),
+            PutValue::Shared(inner.shared_value()),
+            Match::NoValue,
+            &self.map.inner,
+            &guard
+        );
+        ValueSlot::as_inner(value_slot).is_none()
+    }
+
+    /// Removes `value` from the set. Returns true if the set had an equal element present.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// set.insert(1);
+    /// assert!(set.remove(&1));
+    /// assert!(!set.remove(&1));
+    /// ```
+    pub fn remove<Q>(&self, value: &Q) -> bool
+        where Q: ?Sized + Hash + Equivalent<T>,
+    {
+        self.map.remove(value, &pin()).is_some()
+    }
+
+    /// An iterator visiting all elements in arbitrary order, with the same guarantees as
+    /// `LockFreeHashMap::iter()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set: LockFreeHashSet<u32> = vec![4, 8].into_iter().collect();
+    /// let guard = lockfreehashmap::pin();
+    /// let mut elements = set.iter(&guard).collect::<Vec<_>>();
+    /// elements.sort();
+    /// assert_eq!(elements, vec![&4, &8]);
+    /// ```
+    pub fn iter<'s: 'guard>(&'s self, guard: &'guard Guard) -> SetIter<'guard, T, S> {
+        SetIter { keys: self.map.keys(guard) }
+    }
+
+    /// Returns a new set containing a copy of every element that is in `self`, `other` or both.
+    ///
+    /// The new set has the same hasher and settings as `self`. Each set is iterated over once, as
+    /// with `iter()`, so if other threads modify either set at the same time, then the result isn't
+    /// a consistent snapshot of both sets.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let a: LockFreeHashSet<u32> = vec![1, 2].into_iter().collect();
+    /// let b: LockFreeHashSet<u32> = vec![2, 3].into_iter().collect();
+    /// let union = a.union(&b);
+    /// assert_eq!(union.len(), 3);
+    /// assert!((1..4).all(|i| union.contains(&i)));
+    /// ```
+    pub fn union<S2>(&self, other: &LockFreeHashSet<T, S2>) -> Self
+        where T: Clone,
+              S2: BuildHasher + Clone,
+    {
+        let guard = pin();
+        let union = self.empty_copy(self.len().saturating_add(other.len()));
+        for element in self.iter(&guard).chain(other.iter(&guard)) {
+            union.insert(element.clone());
+        }
+        union
+    }
+
+    /// Returns a new set containing a copy of every element that is in both `self` and `other`.
+    ///
+    /// The new set has the same hasher and settings as `self`. Like `union()`, this isn't a
+    /// consistent snapshot of both sets if other threads modify them at the same time.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let a: LockFreeHashSet<u32> = vec![1, 2].into_iter().collect();
+    /// let b: LockFreeHashSet<u32> = vec![2, 3].into_iter().collect();
+    /// let intersection = a.intersection(&b);
+    /// assert_eq!(intersection.len(), 1);
+    /// assert!(intersection.contains(&2));
+    /// ```
+    pub fn intersection<S2>(&self, other: &LockFreeHashSet<T, S2>) -> Self
+        where T: Clone,
+              S2: BuildHasher + Clone,
+    {
+        let guard = pin();
+        let intersection = self.empty_copy(self.len().min(other.len()));
+        for element in self.iter(&guard).filter(|element| other.contains(*element)) {
+            intersection.insert(element.clone());
+        }
+        intersection
+    }
+
+    /// Private helper method that creates an empty set with room for `len` elements, with the same
+    /// hasher, settings and shared value as `self`.
+    fn empty_copy(&self, len: usize) -> Self {
+        let guard = pin();
+        let inner = self.map.load_inner(&guard);
+        let capacity = MapInner::<T, (), S>::min_capacity_for(len);
+        LockFreeHashSet {
+            map: LockFreeHashMap { inner: AtomicBox::new(inner.with_capacity_from(capacity)) },
+        }
+    }
+}
+
+impl<T: Hash + Eq> LockFreeHashSet<T> {
+    /// Creates a new `LockFreeHashSet`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// assert_eq!(set.capacity(), LockFreeHashMap::<u32, ()>::DEFAULT_CAPACITY);
+    /// ```
+    pub fn new() -> Self {
+        Self::with_capacity(LockFreeHashMap::<T, ()>::DEFAULT_CAPACITY)
+    }
+
+    /// Creates a new `LockFreeHashSet` of a given size. Uses the next power of two if size is not
+    /// a power of two.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::with_capacity(12);
+    /// assert_eq!(set.capacity(), 16);
+    /// ```
+    pub fn with_capacity(size: usize) -> Self {
+        Self::with_capacity_and_hasher(size, RandomState::new())
+    }
+}
+
+impl<T, S> Default for LockFreeHashSet<T, S>
+    where T: Hash + Eq,
+          S: BuildHasher + Clone + Default,
+{
+    /// Creates an empty `LockFreeHashSet` with the default capacity, using the default value of
+    /// the hasher.
+    fn default() -> Self {
+        Self::with_capacity_and_hasher(LockFreeHashMap::<T, ()>::DEFAULT_CAPACITY, S::default())
+    }
+}
+
+impl<T, S> Clone for LockFreeHashSet<T, S>
+    where T: Hash + Eq + Clone,
+          S: BuildHasher + Clone,
+{
+    /// Creates a new set containing a copy of every element in `self`, with the same guarantees as
+    /// `LockFreeHashSet::iter()`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// set.insert(1);
+    /// let copy = set.clone();
+    /// set.insert(2);
+    /// assert_eq!(copy.len(), 1);
+    /// assert!(copy.contains(&1));
+    /// ```
+    fn clone(&self) -> Self {
+        let guard = pin();
+        let copy = self.empty_copy(self.len());
+        for element in self.iter(&guard) {
+            copy.insert(element.clone());
+        }
+        copy
+    }
+}
+
+impl<T, S> Extend<T> for LockFreeHashSet<T, S>
+    where T: Hash + Eq,
+          S: BuildHasher + Clone,
+{
+    /// Inserts every element of `iter`.
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for element in iter {
+            self.insert(element);
+        }
+    }
+}
+
+impl<T, S> FromIterator<T> for LockFreeHashSet<T, S>
+    where T: Hash + Eq,
+          S: BuildHasher + Clone + Default,
+{
+    /// Creates a set from the elements of `iter`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set: LockFreeHashSet<u32> = vec![1, 2, 1].into_iter().collect();
+    /// assert_eq!(set.len(), 2);
+    /// ```
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Self::default();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<T, S> fmt::Debug for LockFreeHashSet<T, S>
+    where T: Hash + Eq + fmt::Debug,
+          S: BuildHasher + Clone,
+{
+    /// Formats the elements of the set like a [HashSet](::std::collections::HashSet).
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let set = LockFreeHashSet::<u32>::new();
+    /// set.insert(1);
+    /// assert_eq!(format!("{:?}", set), "{1}");
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let guard = pin();
+        f.debug_set().entries(self.iter(&guard)).finish()
+    }
+}
+
+impl<T, S> PartialEq for LockFreeHashSet<T, S>
+    where T: Hash + Eq,
+          S: BuildHasher + Clone,
+{
+    /// Returns true if both sets contain the same elements. Like `LockFreeHashMap::eq()`, this is
+    /// only meaningful if neither set is modified during the comparison.
+    ///
+    /// # Examples
+    /// ```
+    /// # use lockfreehashmap::*;
+    /// let a: LockFreeHashSet<u32> = (0..10).collect();
+    /// let b: LockFreeHashSet<u32> = (0..10).rev().collect();
+    /// assert_eq!(a, b);
+    /// b.remove(&3);
+    /// assert!(a != b);
+    /// ```
+    fn eq(&self, other: &Self) -> bool {
+        let guard = pin();
+        self.len() == other.len() && self.iter(&guard).all(|element| other.contains(element))
+    }
+}
+
+impl<T, S> Eq for LockFreeHashSet<T, S>
+    where T: Hash + Eq,
+          S: BuildHasher + Clone,
+{
+}
+
+/// An iterator over the elements of a [LockFreeHashSet]. This is created by
+/// `LockFreeHashSet::iter()`.
+#[derive(Debug)]
+pub struct SetIter<'guard, T: 'guard, S: 'guard> {
+    keys: Keys<'guard, 'static, T, (), S>,
+}
+
+impl<'guard, T, S> Iterator for SetIter<'guard, T, S>
+    where T: Hash + Eq,
+          S: BuildHasher + Clone,
+{
+    type Item = &'guard T;
+    fn next(&mut self) -> Option<&'guard T> {
+        self.keys.next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scope;
+    use test::run_threads;
+
+    #[test]
+    fn test_set_during_resizes() {
+        // Every element shares one value, which must survive being removed, copied into newer
+        // maps and dropped with the old ones.
+        let set = &LockFreeHashSet::<u32>::with_capacity(1);
+        scope(|scope| {
+            scope.spawn(move || {
+                for _ in 0..8 {
+                    set.shrink_to_fit();
+                }
+            });
+            run_threads(4, 256, |elements, _| {
+                for i in elements {
+                    assert!(set.insert(i));
+                    if i % 2 == 0 {
+                        assert!(set.remove(&i));
+                    }
+                }
+            });
+        });
+        assert_eq!(set.len(), 512);
+        assert!((0..1024).all(|i| set.contains(&i) == (i % 2 == 1)));
+        let evens: LockFreeHashSet<u32> = (0..1024).filter(|i| i % 2 == 0).collect();
+        assert_eq!(set.union(&evens).len(), 1024);
+        assert_eq!(set.intersection(&evens).len(), 0);
+        set.clear();
+        assert!(set.insert(1));
+        assert!(set.contains(&1));
+    }
+}
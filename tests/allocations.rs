@@ -0,0 +1,100 @@
+// LockFreeHashMap -- A concurrent, lock-free hash map for Rust.
+// Copyright (C) 2018  rolag
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Counts the allocations that the maps make. These tests have their own binary, so that the
+//! counting allocator doesn't replace the allocator of the unit tests.
+
+extern crate lockfreehashmap;
+
+use lockfreehashmap::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Counts the allocations made by the current thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_writes_dont_allocate_value_states() {
+    let allocations = || ALLOCATIONS.with(|allocations| allocations.get());
+    let map = LockFreeHashMap::<u32, u32>::with_capacity(1024);
+    let guard = pin();
+    for i in 0..256 {
+        map.insert(i, i, &guard);
+    }
+    // Tombstones aren't allocated. Only the garbage collector allocates now and then, when
+    // its buffer of deferred drops is full.
+    let before = allocations();
+    for i in 0..256 {
+        assert_eq!(map.remove(&i, &guard), Some(&i));
+    }
+    assert!(allocations() - before < 16, "{} allocations", allocations() - before);
+    // Copying the slots into a newer map doesn't allocate per slot either.
+    for i in 0..256 {
+        map.insert(i, i, &guard);
+    }
+    let before = allocations();
+    map.reserve(4096);
+    assert!(allocations() - before < 16, "{} allocations", allocations() - before);
+    assert!((0..256).all(|i| map.get(&i, &guard) == Some(&i)));
+}
+
+#[test]
+fn test_set_inserts_only_allocate_elements() {
+    let allocations = || ALLOCATIONS.with(|allocations| allocations.get());
+    let set = LockFreeHashSet::<u32>::with_capacity(1024);
+    let before = allocations();
+    for i in 0..256 {
+        assert!(set.insert(i));
+    }
+    assert!(allocations() - before < 256 + 16, "{} allocations", allocations() - before);
+    // Inserting an element that is already there doesn't change its value slot.
+    let before = allocations();
+    for i in 0..256 {
+        assert!(!set.insert(i));
+    }
+    assert!(allocations() - before < 256 + 16, "{} allocations", allocations() - before);
+    assert_eq!(set.len(), 256);
+}
+
+#[test]
+fn test_map_u64_inserts_only_allocate_values() {
+    let allocations = || ALLOCATIONS.with(|allocations| allocations.get());
+    let map = LockFreeHashMapU64::<u32>::with_capacity(1024);
+    let guard = pin();
+    let before = allocations();
+    for i in 0..256 {
+        assert_eq!(map.insert(i, i as u32, &guard), None);
+    }
+    assert!(allocations() - before < 256 + 16, "{} allocations", allocations() - before);
+    assert!((0..256).all(|i| map.get(i, &guard) == Some(&(i as u32))));
+}